@@ -0,0 +1,107 @@
+//! Benchmarks for parsing Cookie headers, using only the API of the first release
+//!
+//! This file builds unchanged against the first release, so the parser can be
+//! compared with it: copy it into a checkout of that release, and run `cargo bench` in each.
+
+extern crate test;
+extern crate http;
+extern crate serialize;
+extern crate iron;
+extern crate "iron-test" as iron_test;
+extern crate "rust-crypto" as crypto;
+extern crate cookie;
+
+use std::fmt::Show;
+use test::Bencher;
+use serialize::hex::ToHex;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::mac::Mac;
+use crypto::hmac::Hmac;
+use iron::Middleware;
+use iron_test::mock::{request, response};
+use cookie::{Cookie, CookieParser};
+
+// Long and random enough to pass the secret checks of later releases
+static SECRET: &'static str = "9c2e47a1d85b3f60e7a24c19b8d053f6a1e97c42d6b8f05e3a7c91d24f68b0e5";
+
+// `CookieParser::signed` returns the parser in the first release, and a `Result` since secrets are checked
+trait SignedParser {
+    fn parser(self) -> CookieParser;
+}
+
+impl SignedParser for CookieParser {
+    fn parser(self) -> CookieParser { self }
+}
+
+impl<E: Show> SignedParser for Result<CookieParser, E> {
+    fn parser(self) -> CookieParser { self.unwrap() }
+}
+
+// Sign as the default signer of every release does, with a hex HMAC SHA-256 of the value
+fn sign(value: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), SECRET.as_bytes());
+    hmac.input(value.as_bytes());
+    let hash: &mut [u8] = [0, ..32];
+    hmac.raw_result(hash);
+    hash.as_slice().to_hex()
+}
+
+fn bench_header(b: &mut Bencher, mut parser: CookieParser, header: String, count: uint) {
+    let mut req = request::new(::http::method::Get, "localhost:3000");
+    req.headers.extensions.insert("Cookie".to_string(), header.clone());
+    let mut res = response::new();
+    parser.enter(&mut req, &mut res);
+    // Every cookie must be kept, or the releases are not doing the same work
+    assert_eq!(req.alloy.find::<Cookie>().unwrap().map.len(), count);
+    b.bytes = header.len() as u64;
+    b.iter(|| parser.enter(&mut req, &mut res));
+}
+
+fn plain_header(count: uint) -> String {
+    let cookies: Vec<String> = range(0u, count).map(|i| format!("cookie{}=value{}", i, i)).collect();
+    cookies.connect("; ")
+}
+
+fn signed_header(count: uint) -> String {
+    let cookies: Vec<String> = range(0u, count).map(|i| {
+        let value = format!("value{}", i);
+        format!("cookie{}=s:{}.{}", i, value, sign(value.as_slice()))
+    }).collect();
+    cookies.connect("; ")
+}
+
+fn escaped_header(count: uint) -> String {
+    let cookies: Vec<String> = range(0u, count).map(|i| format!("cookie%20{}=a%20value%2C%20{}", i, i)).collect();
+    cookies.connect("; ")
+}
+
+#[bench]
+fn baseline_1_cookie(b: &mut Bencher) {
+    bench_header(b, CookieParser::new(), plain_header(1), 1)
+}
+
+#[bench]
+fn baseline_20_cookies(b: &mut Bencher) {
+    bench_header(b, CookieParser::new(), plain_header(20), 20)
+}
+
+#[bench]
+fn baseline_100_cookies(b: &mut Bencher) {
+    bench_header(b, CookieParser::new(), plain_header(100), 100)
+}
+
+#[bench]
+fn baseline_20_escaped_cookies(b: &mut Bencher) {
+    bench_header(b, CookieParser::new(), escaped_header(20), 20)
+}
+
+#[bench]
+fn baseline_20_signed_cookies(b: &mut Bencher) {
+    bench_header(b, CookieParser::signed(SECRET.to_string()).parser(), signed_header(20), 20)
+}
+
+#[bench]
+fn baseline_100_signed_cookies(b: &mut Bencher) {
+    bench_header(b, CookieParser::signed(SECRET.to_string()).parser(), signed_header(100), 100)
+}
@@ -0,0 +1,156 @@
+//! Benchmarks for `CookieParser`, on headers of plain, signed, escaped and JSON cookies
+
+extern crate test;
+extern crate http;
+extern crate iron;
+extern crate "iron-test" as iron_test;
+extern crate cookie;
+
+use test::Bencher;
+use iron::{Request, Middleware};
+use iron_test::mock::{request, response};
+use cookie::{Cookie, CookieParser, LazyCookie, Strict, Percent, HmacSha256, HmacSha512};
+
+// A header as sent by a browser to a site with analytics, a session and preferences
+static BROWSER_HEADER: &'static str =
+    "_ga=GA1.2.1234567890.1234567890; _gid=GA1.2.987654321.1234567890; _gat=1; \
+     session=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; \
+     lang=en-US; tz=America%2FNew_York; prefs=j%3A%7B%22theme%22%3A%22dark%22%7D";
+
+// A header whose names and values all need decoding
+static ESCAPED_HEADER: &'static str =
+    "a%20b=c%20d; e%2Bf=g%2Bh; path=%2Fa%2Fb%2Fc; q=%E2%9C%93%E2%9C%93%E2%9C%93; \
+     list=1%2C2%2C3%2C4%2C5%2C6; quoted=%22thing%22";
+
+fn request_with(header: &str) -> Request {
+    let mut req = request::new(::http::method::Get, "localhost:3000");
+    req.headers.extensions.insert("Cookie".to_string(), header.to_string());
+    req
+}
+
+fn bench_parser(b: &mut Bencher, mut parser: CookieParser, header: &str) {
+    let mut req = request_with(header);
+    let mut res = response::new();
+    b.bytes = header.len() as u64;
+    b.iter(|| parser.enter(&mut req, &mut res));
+}
+
+#[bench]
+fn parse_browser_header(b: &mut Bencher) {
+    bench_parser(b, CookieParser::new(), BROWSER_HEADER)
+}
+
+#[bench]
+fn parse_signed_header(b: &mut Bencher) {
+    bench_parser(b, CookieParser::signed_unchecked("@zzmp".to_string()), BROWSER_HEADER)
+}
+
+#[bench]
+fn parse_strict_header(b: &mut Bencher) {
+    bench_parser(b, CookieParser::new().validation(Strict), BROWSER_HEADER)
+}
+
+#[bench]
+fn parse_escaped_header(b: &mut Bencher) {
+    bench_parser(b, CookieParser::new(), ESCAPED_HEADER)
+}
+
+#[bench]
+fn parse_lazy_single(b: &mut Bencher) {
+    // Find only the session, as most handlers would
+    let parser = CookieParser::signed_unchecked("@zzmp".to_string());
+    b.bytes = BROWSER_HEADER.len() as u64;
+    b.iter(|| {
+        let mut lazy = LazyCookie::new(parser.clone(), Some(BROWSER_HEADER.to_string()));
+        lazy.get("session").map(|session| session.len())
+    });
+}
+
+// A header of `count` unsigned cookies
+fn plain_header(count: uint) -> String {
+    let cookies: Vec<String> = range(0u, count).map(|i| format!("cookie{}=value{}", i, i)).collect();
+    cookies.connect("; ")
+}
+
+// A header of `count` cookies, each signed with `secret`
+fn signed_header(secret: &str, count: uint) -> String {
+    let signer = Cookie::new(Some(secret.to_string()));
+    let cookies: Vec<String> = range(0u, count).map(|i| {
+        let (name, value) = (format!("cookie{}", i), format!("value{}", i));
+        let signature = signer.sign(name.as_slice(), &value).unwrap();
+        format!("{}=s:{}.{}", name, value, signature)
+    }).collect();
+    cookies.connect("; ")
+}
+
+// A header of `count` JSON cookies, each an object of a few fields, as set by `set_json_cookie`
+fn json_header(count: uint) -> String {
+    let cookies: Vec<String> = range(0u, count).map(|i| {
+        let json = format!("j:{{\"id\":{},\"name\":\"user {}\",\"roles\":[\"read\",\"write\"],\"flags\":{{\"beta\":true,\"theme\":null}}}}", i, i);
+        format!("json{}={}", i, Percent.encode(json.as_slice()))
+    }).collect();
+    cookies.connect("; ")
+}
+
+#[bench]
+fn parse_1_cookie(b: &mut Bencher) {
+    bench_parser(b, CookieParser::new(), plain_header(1).as_slice())
+}
+
+#[bench]
+fn parse_20_cookies(b: &mut Bencher) {
+    bench_parser(b, CookieParser::new(), plain_header(20).as_slice())
+}
+
+#[bench]
+fn parse_100_cookies(b: &mut Bencher) {
+    bench_parser(b, CookieParser::new(), plain_header(100).as_slice())
+}
+
+#[bench]
+fn parse_1_signed_cookie(b: &mut Bencher) {
+    bench_parser(b, CookieParser::signed_unchecked("@zzmp".to_string()), signed_header("@zzmp", 1).as_slice())
+}
+
+#[bench]
+fn parse_20_signed_cookies(b: &mut Bencher) {
+    bench_parser(b, CookieParser::signed_unchecked("@zzmp".to_string()), signed_header("@zzmp", 20).as_slice())
+}
+
+#[bench]
+fn parse_100_signed_cookies(b: &mut Bencher) {
+    bench_parser(b, CookieParser::signed_unchecked("@zzmp".to_string()), signed_header("@zzmp", 100).as_slice())
+}
+
+#[bench]
+fn parse_signed_cookies_during_transition(b: &mut Bencher) {
+    // Signed with SHA-256, while moving to SHA-512
+    let header = signed_header("@zzmp", 20);
+    let parser = CookieParser::signed_unchecked("@zzmp".to_string()).signer(HmacSha512).accept(HmacSha256);
+    bench_parser(b, parser, header.as_slice())
+}
+
+#[bench]
+fn parse_1_json_cookie(b: &mut Bencher) {
+    bench_parser(b, CookieParser::new(), json_header(1).as_slice())
+}
+
+#[bench]
+fn parse_20_json_cookies(b: &mut Bencher) {
+    bench_parser(b, CookieParser::new(), json_header(20).as_slice())
+}
+
+#[bench]
+fn parse_100_json_cookies(b: &mut Bencher) {
+    bench_parser(b, CookieParser::new(), json_header(100).as_slice())
+}
+
+#[bench]
+fn sign_cookie(b: &mut Bencher) {
+    let mut req = request_with("");
+    let mut parser = CookieParser::signed_unchecked("@zzmp".to_string());
+    parser.enter(&mut req, &mut response::new());
+    let signer = req.alloy.find::<Cookie>().unwrap();
+    let value = "a session identifier".to_string();
+    b.iter(|| signer.sign("session", &value));
+}
@@ -0,0 +1,95 @@
+//! Benchmarks for `SetCookie` and `HeaderCollection::to_cookie_av`, on plain, signed and JSON cookies
+
+extern crate test;
+extern crate time;
+extern crate serialize;
+extern crate iron;
+extern crate "iron-test" as iron_test;
+extern crate cookie;
+
+use std::collections::TreeMap;
+use test::Bencher;
+use serialize::json;
+use iron_test::mock::response;
+use cookie::{Cookie, SetCookie, HeaderCollection};
+
+// The headers of a typical session cookie, with every attribute set
+fn full_headers() -> HeaderCollection {
+    let mut extensions = TreeMap::new();
+    extensions.insert("SameSite".to_string(), Some("Lax".to_string()));
+    extensions.insert("Priority".to_string(), Some("High".to_string()));
+    HeaderCollection {
+        expires: Some(time::at_utc(time::Timespec::new(1500000000, 0))),
+        max_age: Some(3600),
+        domain: Some("example.com".to_string()),
+        path: Some("/".to_string()),
+        secure: true,
+        http_only: true,
+        extensions: Some(extensions)
+    }
+}
+
+// Set `count` cookies on a single response, as a login handler might
+fn bench_set_cookies(b: &mut Bencher, signer: &Cookie, count: uint) {
+    let cookies: Vec<(String, String)> = range(0u, count)
+        .map(|i| (format!("cookie{}", i), format!("value {}", i)))
+        .collect();
+    let mut res = response::new();
+    b.iter(|| {
+        for cookie in cookies.iter() {
+            res.set_cookie(signer, cookie.clone(), HeaderCollection::secured()).unwrap();
+        }
+    });
+}
+
+#[bench]
+fn to_cookie_av_empty(b: &mut Bencher) {
+    b.iter(|| HeaderCollection::empty().to_cookie_av());
+}
+
+#[bench]
+fn to_cookie_av_full(b: &mut Bencher) {
+    b.iter(|| full_headers().to_cookie_av());
+}
+
+#[bench]
+fn set_1_cookie(b: &mut Bencher) {
+    bench_set_cookies(b, &Cookie::new(None), 1)
+}
+
+#[bench]
+fn set_20_cookies(b: &mut Bencher) {
+    bench_set_cookies(b, &Cookie::new(None), 20)
+}
+
+#[bench]
+fn set_100_cookies(b: &mut Bencher) {
+    bench_set_cookies(b, &Cookie::new(None), 100)
+}
+
+#[bench]
+fn set_1_signed_cookie(b: &mut Bencher) {
+    bench_set_cookies(b, &Cookie::new(Some("@zzmp".to_string())), 1)
+}
+
+#[bench]
+fn set_20_signed_cookies(b: &mut Bencher) {
+    bench_set_cookies(b, &Cookie::new(Some("@zzmp".to_string())), 20)
+}
+
+#[bench]
+fn set_100_signed_cookies(b: &mut Bencher) {
+    bench_set_cookies(b, &Cookie::new(Some("@zzmp".to_string())), 100)
+}
+
+#[bench]
+fn set_json_cookie(b: &mut Bencher) {
+    let signer = Cookie::new(None);
+    let value = json::from_str(
+        r#"{"id":42,"name":"user 42","roles":["read","write"],"flags":{"beta":true,"theme":null}}"#
+    ).unwrap();
+    let mut res = response::new();
+    b.iter(|| {
+        res.set_json_cookie(&signer, ("json".to_string(), value.clone()), HeaderCollection::secured()).unwrap()
+    });
+}
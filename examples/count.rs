@@ -5,7 +5,7 @@ extern crate cookie;
 use std::io::net::ip::Ipv4Addr;
 use http::status::Ok;
 use iron::{Iron, Server, Chain, Request, Response, Status, Continue, FromFn};
-use cookie::{CookieParser, Cookie, SetCookie, HeaderCollection};
+use cookie::{secret_file, CookieParser, Cookie, SetCookie, HeaderCollection};
 
 fn count_views(req: &mut Request, res: &mut Response) -> Status {
     // Only hold on to cookies for ten seconds
@@ -22,12 +22,12 @@ fn count_views(req: &mut Request, res: &mut Response) -> Status {
                     let count = (cnt.unwrap() + 1).to_string();
                     println!("COOKIE COUNT: {}", i)
                     // Override the cookie with a new value
-                    res.set_cookie(cookie, ("count".to_string(), count.clone()), options);
+                    res.set_cookie(cookie, ("count".to_string(), count.clone()), options).unwrap();
                     let _ = res.serve(Ok, format!("Hit Counter: {}", count).as_slice());
                 },
                 _       => {
                     // Initialize our cookie counter
-                    res.set_cookie(cookie, ("count".to_string(), "1".to_string()), options);
+                    res.set_cookie(cookie, ("count".to_string(), "1".to_string()), options).unwrap();
                     let _ = res.serve(Ok, format!("Hit Counter: {}", 1u8).as_slice());
                 }
             }
@@ -39,7 +39,9 @@ fn count_views(req: &mut Request, res: &mut Response) -> Status {
 
 fn main() {
     let mut server: Server = Iron::new();
-    server.chain.link(CookieParser::signed("@zzmp".to_string()));
+    // Sign cookies with the secret in `cookie.key`, generated on the first run
+    let secret = secret_file(&Path::new("cookie.key")).unwrap();
+    server.chain.link(CookieParser::signed(secret).unwrap());
     server.chain.link(FromFn::new(count_views));
     server.listen(Ipv4Addr(127, 0, 0, 1), 3000);
 }
@@ -9,7 +9,7 @@ use std::io::net::ip::Ipv4Addr;
 use serialize::json::Number;
 use http::status::Ok;
 use iron::{Iron, Server, Chain, Request, Response, Status, Continue, FromFn};
-use cookie::{CookieParser, Cookie, SetCookie, HeaderCollection};
+use cookie::{secret_file, CookieParser, Cookie, SetCookie, HeaderCollection};
 
 fn count_views(req: &mut Request, res: &mut Response) -> Status {
     // Only hold on to cookies for ten seconds
@@ -27,12 +27,12 @@ fn count_views(req: &mut Request, res: &mut Response) -> Status {
                     let count = cnt.to_string();
                     println!("COOKIE COUNT: {}", count)
                     // Override the cookie with a new value
-                    res.set_json_cookie(cookie, ("count".to_string(), Number(cnt)), options);
+                    res.set_json_cookie(cookie, ("count".to_string(), Number(cnt)), options).unwrap();
                     let _ = res.serve(Ok, format!("Hit Counter: {}", count).as_slice());
                 },
                 _       => {
                     // Initialize our cookie counter
-                    res.set_json_cookie(cookie, ("count".to_string(), Number(1f64)), options);
+                    res.set_json_cookie(cookie, ("count".to_string(), Number(1f64)), options).unwrap();
                     let _ = res.serve(Ok, format!("Hit Counter: {}", 1f64).as_slice());
                 }
             }
@@ -44,7 +44,9 @@ fn count_views(req: &mut Request, res: &mut Response) -> Status {
 
 fn main() {
     let mut server: Server = Iron::new();
-    server.chain.link(CookieParser::signed("@zzmp".to_string()));
+    // Sign cookies with the secret in `cookie.key`, generated on the first run
+    let secret = secret_file(&Path::new("cookie.key")).unwrap();
+    server.chain.link(CookieParser::signed(secret).unwrap());
     server.chain.link(FromFn::new(count_views));
     server.listen(Ipv4Addr(127, 0, 0, 1), 3000);
 }
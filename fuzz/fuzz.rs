@@ -0,0 +1,202 @@
+//! Fuzz targets for parsing and setting cookies
+//!
+//! Each target is run over its seed corpus in `fuzz/corpus/<target>`, then over
+//! random mutations of it. Any input which fails is written to `fuzz/crashes`,
+//! to be turned into a regression test and added to the corpus.
+//!
+//! `cargo test --test fuzz` runs a short, reproducible pass. Set `FUZZ_ITERATIONS`
+//! for a longer run, and `FUZZ_SEED` to explore other mutations.
+
+extern crate serialize;
+extern crate http;
+extern crate iron;
+extern crate "iron-test" as iron_test;
+extern crate cookie;
+
+use std::os;
+use std::task;
+use std::io::fs;
+use std::io::File;
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+use serialize::json;
+use iron_test::mock::response;
+use cookie::{Cookie, CookieParser, LazyCookie, SetCookie, HeaderCollection, ValueCodec};
+use cookie::{Strict, LastWins, Base64Url, Percent, ExpressSignature, HmacSha512, RailsCodec};
+
+static SECRET: &'static str = "f3a1c94e0b7d2e68a5c0d9b4e1f7a23c";
+
+// Mutations performed for each target, unless `FUZZ_ITERATIONS` is set
+static ITERATIONS: uint = 1000;
+
+// Bytes likely to reach unusual paths: delimiters, escapes and multibyte characters
+static INTERESTING: &'static [&'static [u8]] = &[
+    b";", b"=", b"\"", b"%", b"%E2%82%AC", b".", b"s:", b"s%3A", b"j:", b"$", b"{", b"}",
+    b"[", b"]", b"\\", b" ", b"\t", b"\xe2\x82\xac", b"\xff", b"--", b"__Host-", b"__Secure-"
+];
+
+// The parsers each header is run through
+fn parsers() -> Vec<CookieParser> {
+    vec![
+        CookieParser::new(),
+        CookieParser::new().validation(Strict).duplicates(LastWins),
+        CookieParser::new().max_size(64),
+        CookieParser::signed_unchecked(SECRET.to_string()),
+        CookieParser::signed_unchecked(SECRET.to_string()).encoding(Base64Url),
+        CookieParser::signed_unchecked(SECRET.to_string()).signature_format(ExpressSignature).accept(HmacSha512),
+        CookieParser::new().codec(RailsCodec::signed(SECRET))
+    ]
+}
+
+// Parse the input as a Cookie header, lazily and otherwise.
+fn parse_header(data: &[u8]) {
+    let header = String::from_utf8_lossy(data).into_string();
+    for parser in parsers().into_iter() {
+        let cookie = parser.parse(header.as_slice());
+        let mut lazy = LazyCookie::new(parser.clone(), Some(header.clone()));
+        for name in cookie.map.keys() {
+            assert_eq!(lazy.get(name.as_slice()).map(|val| val.clone()), cookie.get(name.as_slice()).map(|val| val.clone()));
+        }
+    }
+}
+
+// Set a cookie from the input, as lines of name, value and path, and parse it back.
+fn set_cookie(data: &[u8]) {
+    let input = String::from_utf8_lossy(data).into_string();
+    let mut lines = input.as_slice().lines();
+    let name = lines.next().unwrap_or("").to_string();
+    let value = lines.next().unwrap_or("").to_string();
+    let path = lines.next().map(|path| path.to_string());
+    for secret in [None, Some(SECRET.to_string())].iter() {
+        let signer = Cookie::new(secret.clone());
+        let parser = match *secret {
+            Some(ref secret) => CookieParser::signed_unchecked(secret.clone()),
+            None             => CookieParser::new()
+        };
+        let mut options = HeaderCollection::secured();
+        options.path = path.clone();
+        let mut res = response::new();
+        if res.set_cookie(&signer, (name.clone(), value.clone()), options).is_err() { continue }
+        let header = res.headers.extensions.find(&"Set-Cookie".to_string()).unwrap().clone();
+        let pair = header.as_slice().split(';').next().unwrap_or("");
+        let cookie = parser.parse(pair);
+        // Simple names always come back with the value they were set with
+        let simple = !name.is_empty() && name.as_slice().chars().all(|c| c.is_alphanumeric());
+        if simple {
+            assert_eq!(cookie.get(name.as_slice()), Some(&value));
+        }
+        match json::from_str(value.as_slice()) {
+            Ok(json) => {
+                let mut res = response::new();
+                let _ = res.set_json_cookie(&signer, (name.clone(), json), HeaderCollection::empty());
+                let header = res.headers.extensions.find(&"Set-Cookie".to_string()).unwrap().clone();
+                parser.parse(header.as_slice().split(';').next().unwrap_or(""));
+            },
+            Err(_)   => ()
+        }
+    }
+}
+
+// Decode the input as a JSON cookie, and as the value of codecs storing JSON messages.
+fn json_cookie(data: &[u8]) {
+    let value = String::from_utf8_lossy(data).into_string();
+    let parser = CookieParser::new();
+    parser.parse(format!("thing=j:{}", value).as_slice());
+    parser.parse(format!("thing=j%3A{}", Percent.encode(value.as_slice())).as_slice());
+    for codec in [RailsCodec::signed(SECRET), RailsCodec::encrypted(SECRET).unwrap()].iter() {
+        codec.decode("thing", value.as_slice());
+        let encoded = codec.encode("thing", value.as_slice());
+        if !value.as_slice().starts_with("j:") {
+            assert_eq!(codec.decode("thing", encoded.as_slice()), Some(value.clone()));
+        }
+    }
+}
+
+// Return true if the target fails on the input
+fn fails(target: fn(&[u8]), input: Vec<u8>) -> bool {
+    task::try(proc() target(input.as_slice())).is_err()
+}
+
+fn corpus(name: &str) -> Vec<Vec<u8>> {
+    let dir = Path::new("fuzz/corpus").join(name);
+    let mut paths = fs::readdir(&dir).unwrap();
+    paths.sort();
+    paths.iter().map(|path| File::open(path).read_to_end().unwrap()).collect()
+}
+
+fn mutate<R: Rng>(rng: &mut R, corpus: &[Vec<u8>]) -> Vec<u8> {
+    let mut input = rng.choose(corpus).unwrap().clone();
+    for _ in range(0, rng.gen_range(1u, 5)) {
+        let at = rng.gen_range(0, input.len() + 1);
+        match rng.gen_range(0u, 5) {
+            // Insert interesting bytes
+            0 => {
+                let bytes = *rng.choose(INTERESTING).unwrap();
+                let tail = input.slice_from(at).to_vec();
+                input.truncate(at);
+                input.push_all(bytes);
+                input.push_all(tail.as_slice());
+            },
+            // Replace a byte
+            1 if at < input.len() => *input.get_mut(at) = rng.gen(),
+            // Remove some bytes
+            2 if at < input.len() => {
+                let end = rng.gen_range(at, input.len()) + 1;
+                let tail = input.slice_from(end).to_vec();
+                input.truncate(at);
+                input.push_all(tail.as_slice());
+            },
+            // Splice in part of another input
+            3 => {
+                let other = rng.choose(corpus).unwrap();
+                let from = rng.gen_range(0, other.len() + 1);
+                let tail = input.slice_from(at).to_vec();
+                input.truncate(at);
+                input.push_all(other.slice_from(from));
+                input.push_all(tail.as_slice());
+            },
+            // Truncate
+            _ => input.truncate(at)
+        }
+    }
+    input
+}
+
+fn fuzz(name: &str, target: fn(&[u8])) {
+    let iterations = os::getenv("FUZZ_ITERATIONS")
+        .and_then(|n| from_str(n.as_slice()))
+        .unwrap_or(ITERATIONS);
+    let seed = os::getenv("FUZZ_SEED")
+        .and_then(|n| from_str(n.as_slice()))
+        .unwrap_or(0x636f6f6bu32);
+    let corpus = corpus(name);
+    let mut rng: XorShiftRng = SeedableRng::from_seed([seed | 1, 0x6b69655f, 0x66757a7a, 0x2d727321]);
+
+    let inputs = corpus.clone().into_iter().chain(range(0, iterations).map(|_| mutate(&mut rng, corpus.as_slice())));
+    let mut crashes = Vec::new();
+    for input in inputs {
+        if fails(target, input.clone()) { crashes.push(input) }
+    }
+    if crashes.is_empty() { return }
+
+    let dir = Path::new("fuzz/crashes");
+    fs::mkdir_recursive(&dir, std::io::USER_RWX).unwrap();
+    for (i, input) in crashes.iter().enumerate() {
+        File::create(&dir.join(format!("{}-{}-{}", name, seed, i))).write(input.as_slice()).unwrap();
+    }
+    fail!("{} inputs failed {}, written to fuzz/crashes", crashes.len(), name);
+}
+
+#[test]
+fn fuzz_parse_header() {
+    fuzz("parse_header", parse_header)
+}
+
+#[test]
+fn fuzz_set_cookie() {
+    fuzz("set_cookie", set_cookie)
+}
+
+#[test]
+fn fuzz_json_cookie() {
+    fuzz("json_cookie", json_cookie)
+}
@@ -0,0 +1,291 @@
+//! Sign, verify and decode cookie values, for debugging
+//!
+//! Secrets are read from a key file, such as that written by `secret_file`,
+//! so that they stay out of shell history.
+
+extern crate getopts;
+extern crate serialize;
+extern crate cookie;
+
+use std::io;
+use std::os;
+use getopts::{optopt, optflag, getopts, usage, Matches};
+use serialize::json;
+use serialize::json::{Json, Null};
+use cookie::{Cookie, CookieParser, read_secret, check_secret, lint_dump, Verified};
+use cookie::{Percent, Base64Url, Raw, HexSignature, ExpressSignature};
+use cookie::{HmacSha1, HmacSha256, HmacSha384, HmacSha512, Blake2bMac};
+use cookie::LintWarning;
+
+static USAGE: &'static str = "Usage: cookie-tool <command> [options] [value]
+
+Commands:
+    sign      Sign a value, as `SetCookie` would
+    verify    Verify a signed `s:` value
+    decode    Decode a percent-encoded or `j:` JSON value
+    header    Pretty-print every cookie of a Cookie header
+    lint      Check Set-Cookie headers, such as from `curl -i`, for common mistakes
+
+The value is read from standard input when not given.";
+
+fn main() {
+    match run(os::args().tail()) {
+        Ok(true)       => (),
+        Ok(false)      => os::set_exit_status(1),
+        Err(message)   => {
+            let _ = io::stderr().write_line(format!("cookie-tool: {}", message).as_slice());
+            os::set_exit_status(2)
+        }
+    }
+}
+
+// Run a command, returning false if a value could not be verified
+fn run(args: &[String]) -> Result<bool, String> {
+    let opts = [
+        optopt("k", "secret-file", "read the signing secret from FILE", "FILE"),
+        optopt("n", "name", "the cookie name, for sign and verify (default: cookie)", "NAME"),
+        optopt("s", "signer", "sign with sha1, sha256, sha384, sha512 or blake2b (default: sha256)", "TAG"),
+        optopt("f", "format", "write signatures as hex or express (default: hex)", "FORMAT"),
+        optopt("e", "encoding", "encode values as percent, base64url or raw (default: percent)", "ENCODING"),
+        optflag("h", "help", "print this help")
+    ];
+    let matches = try!(getopts(args, opts).map_err(|err| err.to_string()));
+    if matches.opt_present("h") || matches.free.is_empty() {
+        println!("{}", usage(USAGE, opts));
+        return Ok(true)
+    }
+
+    let parser = try!(parser(&matches));
+    let signed = matches.opt_present("k");
+    let name = matches.opt_str("n").unwrap_or("cookie".to_string());
+    let value = match matches.free.as_slice().get(1) {
+        Some(value) => value.clone(),
+        None        => try!(io::stdin().read_to_string().map_err(|err| err.to_string())).as_slice().trim_right().to_string()
+    };
+    match matches.free.as_slice()[0].as_slice() {
+        "sign"    => {
+            if !signed { return Err("signing needs a --secret-file".to_string()) }
+            println!("{}", sign(&parser, name.as_slice(), value));
+            Ok(true)
+        },
+        "verify"  => {
+            if !signed { return Err("verifying needs a --secret-file".to_string()) }
+            let cookie = parser.parse(pair(&parser, name.as_slice(), value.as_slice()).as_slice());
+            print!("{}", describe(&cookie));
+            Ok(cookie.verification(name.as_slice()) == Some(Verified))
+        },
+        "decode"  => {
+            println!("{}", decode(&parser, value.as_slice()));
+            Ok(true)
+        },
+        "header"  => {
+            print!("{}", describe(&parser.parse(value.as_slice())));
+            Ok(true)
+        },
+        "lint"    => {
+            let results = lint_dump(value.as_slice());
+            print!("{}", describe_lint(results.as_slice()));
+            Ok(results.iter().all(|&(_, ref warnings)| warnings.is_empty()))
+        },
+        command   => Err(format!("unknown command `{}`, see --help", command))
+    }
+}
+
+// Configure a parser from the options, accepting signatures by any signer
+fn parser(matches: &Matches) -> Result<CookieParser, String> {
+    let parser = match matches.opt_str("k") {
+        Some(path) => {
+            let secret = try!(read_secret(&Path::new(path)).map_err(|err| format!("could not read the secret: {}", err)));
+            match check_secret(secret.as_slice()) {
+                Ok(())      => (),
+                Err(reason) => {
+                    let _ = io::stderr().write_line(format!("cookie-tool: warning: weak secret ({})", reason).as_slice());
+                }
+            }
+            CookieParser::signed_unchecked(secret)
+        },
+        None       => CookieParser::new()
+    };
+    let parser = match matches.opt_str("e").as_ref().map(|encoding| encoding.as_slice()) {
+        None | Some("percent") => parser.encoding(Percent),
+        Some("base64url")      => parser.encoding(Base64Url),
+        Some("raw")            => parser.encoding(Raw),
+        Some(encoding)         => return Err(format!("unknown encoding `{}`", encoding))
+    };
+    let parser = match matches.opt_str("f").as_ref().map(|format| format.as_slice()) {
+        None | Some("hex") => parser.signature_format(HexSignature),
+        Some("express")    => parser.signature_format(ExpressSignature),
+        Some(format)       => return Err(format!("unknown signature format `{}`", format))
+    };
+    let parser = parser.accept(HmacSha1).accept(HmacSha256).accept(HmacSha384).accept(HmacSha512).accept(Blake2bMac);
+    match matches.opt_str("s").as_ref().map(|tag| tag.as_slice()) {
+        Some("sha1")           => Ok(parser.signer(HmacSha1)),
+        None | Some("sha256")  => Ok(parser.signer(HmacSha256)),
+        Some("sha384")         => Ok(parser.signer(HmacSha384)),
+        Some("sha512")         => Ok(parser.signer(HmacSha512)),
+        Some("blake2b")        => Ok(parser.signer(Blake2bMac)),
+        Some(tag)              => Err(format!("unknown signer `{}`", tag))
+    }
+}
+
+// A cookie configured by the parser, to sign and encode with
+fn config(parser: &CookieParser) -> Cookie {
+    parser.parse("")
+}
+
+// Sign a value, writing it as `SetCookie` would
+fn sign(parser: &CookieParser, name: &str, value: String) -> String {
+    let cookie = config(parser);
+    let signature = cookie.sign(name, &value).unwrap();
+    "s:".to_string().append(cookie.encoding.encode(value.as_slice()).as_slice()).append(".").append(signature.as_slice())
+}
+
+// A cookie-pair of the encoded `name` and `value`, as sent by a browser
+fn pair(parser: &CookieParser, name: &str, value: &str) -> String {
+    config(parser).encoding.encode_name(name).append("=").append(value)
+}
+
+fn decode(parser: &CookieParser, value: &str) -> String {
+    let decoded = match config(parser).encoding.decode(value) {
+        Some(bytes) => String::from_utf8_lossy(bytes.as_slice()).into_string(),
+        None        => value.to_string()
+    };
+    pretty(decoded)
+}
+
+// Pretty-print `j:` JSON values
+fn pretty(value: String) -> String {
+    if value.as_slice().starts_with("j:") {
+        match json::from_str(value.as_slice().slice_from(2)) {
+            Ok(json) => return json.to_pretty_str(),
+            Err(_)   => ()
+        }
+    }
+    value
+}
+
+// Describe each cookie kept, discarded as a duplicate, rejected or removed by the parser
+fn describe(cookie: &Cookie) -> String {
+    let mut description = String::new();
+    for (i, &(ref name, ref value)) in cookie.pairs.iter().enumerate() {
+        let status = if kept(cookie, name.as_slice()).contains(&i) {
+            cookie.verification(name.as_slice()).unwrap().to_string()
+        } else {
+            "discarded duplicate".to_string()
+        };
+        description.push_str(format!("{} ({}):\n{}\n", name, status, indent(pretty(value.clone()))).as_slice());
+    }
+    for &(ref name, ref reason) in cookie.rejected.iter() {
+        description.push_str(format!("{} (rejected: {})\n", name, reason).as_slice());
+    }
+    for &(ref name, ref outcome) in cookie.outcomes.iter() {
+        if !cookie.pairs.iter().any(|&(ref kept, _)| kept == name) {
+            description.push_str(format!("{} (removed: {})\n", name, outcome).as_slice());
+        }
+    }
+    description
+}
+
+// Find the indices in `cookie.pairs` of the values the parser's `DuplicatePolicy` kept for `name`,
+// in `cookie.map` or, for JSON, in `cookie.json`
+fn kept(cookie: &Cookie, name: &str) -> Vec<uint> {
+    let mut plain = cookie.get(name);
+    let mut parsed = cookie.json.find(&name.to_string());
+    let mut kept = Vec::new();
+    for (i, &(ref key, ref value)) in cookie.pairs.iter().enumerate() {
+        if key.as_slice() != name { continue }
+        if plain == Some(value) {
+            kept.push(i);
+            plain = None;
+        } else if parsed.is_some() && value.as_slice().starts_with("j:") && parsed == Some(&json_value(value.as_slice())) {
+            kept.push(i);
+            parsed = None;
+        }
+    }
+    kept
+}
+
+// Parse a `j:` value as the parser does, reading invalid JSON as `null`
+fn json_value(value: &str) -> Json {
+    json::from_str(value.slice_from(2)).unwrap_or(Null)
+}
+
+// Describe the problems found with each Set-Cookie header
+fn describe_lint(results: &[(String, Vec<LintWarning>)]) -> String {
+    let mut description = String::new();
+    for &(ref header, ref warnings) in results.iter() {
+        let status = if warnings.is_empty() { "ok" } else { "warning" };
+        description.push_str(format!("{}: {}\n", status, header).as_slice());
+        for warning in warnings.iter() {
+            description.push_str(format!("    {}\n", warning.message()).as_slice());
+        }
+    }
+    description
+}
+
+fn indent(value: String) -> String {
+    let lines: Vec<String> = value.as_slice().lines().map(|line| "    ".to_string().append(line)).collect();
+    lines.connect("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use serialize::json;
+    use cookie::{CookieParser, RejectDuplicates};
+    use cookie::lint_dump;
+    use super::{sign, pair, decode, describe, describe_lint};
+
+    #[test]
+    fn check_sign() {
+        let parser = CookieParser::signed_unchecked("@zzmp".to_string());
+        assert_eq!(sign(&parser, "thing", "thung".to_string()),
+            "s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb".to_string());
+        let signed = sign(&parser, "thing", "a b".to_string());
+        let cookie = parser.parse(pair(&parser, "a;b", signed.as_slice()).as_slice());
+        assert_eq!(cookie.get("a;b"), Some(&"a b".to_string()));
+    }
+
+    #[test]
+    fn check_decode() {
+        let parser = CookieParser::new();
+        assert_eq!(decode(&parser, "a%20b"), "a b".to_string());
+        // JSON is pretty-printed
+        let pretty = decode(&parser, "j%3A%7B%22a%22%3A%5B1%2C2%5D%7D");
+        assert!(pretty.as_slice().contains_char('\n'));
+        assert_eq!(json::from_str(pretty.as_slice()).ok(), json::from_str("{\"a\":[1,2]}").ok());
+    }
+
+    #[test]
+    fn check_describe() {
+        let parser = CookieParser::signed_unchecked("@zzmp".to_string());
+        let cookie = parser.parse("thing=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; \
+                                   other=s:thung.00; plain=thung");
+        assert_eq!(describe(&cookie),
+            "thing (Verified):\n    thung\nother (removed: InvalidSignature)\nplain (removed: Unsigned)\n".to_string());
+    }
+
+    #[test]
+    fn check_describe_duplicates() {
+        let header = "session=first; other=thing; session=second";
+        assert_eq!(describe(&CookieParser::new().parse(header)),
+            "session (Unsigned):\n    first\n\
+             other (Unsigned):\n    thing\n\
+             session (discarded duplicate):\n    second\n".to_string());
+        // No value is kept when duplicates are rejected
+        assert_eq!(describe(&CookieParser::new().duplicates(RejectDuplicates).parse(header)),
+            "session (discarded duplicate):\n    first\n\
+             other (Unsigned):\n    thing\n\
+             session (discarded duplicate):\n    second\n".to_string());
+    }
+
+    #[test]
+    fn check_describe_lint() {
+        let results = lint_dump("Set-Cookie: theme=dark\nSet-Cookie: sid=abc; Http-Only\n");
+        assert_eq!(describe_lint(results.as_slice()),
+            "ok: theme=dark\n\
+             warning: sid=abc; Http-Only\n    \
+             non-standard attribute `Http-Only`\n    \
+             session cookie is not Secure\n    \
+             session cookie is not HttpOnly\n".to_string());
+    }
+}
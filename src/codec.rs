@@ -0,0 +1,221 @@
+//! Value encoding - how cookie data is written and read
+
+use url::percent_decode;
+use serialize::json;
+use serialize::base64::{ToBase64, FromBase64, URL_SAFE, Config};
+use super::{Verification, InvalidSignature};
+
+/// The encoding of cookie names and values.
+///
+/// This is shared by `SetCookie` and `CookieParser` through the `Cookie`,
+/// so that any value set will be read back unchanged.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Encoding {
+    /// Percent-encode all but alphanumerics and `*-._~`.
+    ///
+    /// Spaces are written as `%20`, and `+` is never read as a space.
+    /// This is the default.
+    Percent,
+    /// Write values as URL-safe base64, without padding.
+    ///
+    /// Names are percent-encoded.
+    Base64Url,
+    /// Write and read names and values unchanged.
+    ///
+    /// This is for cookies shared with other systems, which use their own encoding.
+    /// Names and values set this way must already be valid in a cookie.
+    Raw
+}
+
+impl Encoding {
+    /// Encode a cookie value.
+    pub fn encode(&self, value: &str) -> String {
+        match *self {
+            Percent   => percent_encode(value),
+            Base64Url => value.as_bytes().to_base64(base64_config()),
+            Raw       => value.to_string()
+        }
+    }
+
+    /// Decode a cookie value into its raw bytes.
+    ///
+    /// Returns `None` if the value could not have been produced by `encode`.
+    pub fn decode(&self, value: &str) -> Option<Vec<u8>> {
+        match *self {
+            Percent   => Some(percent_decode(value.as_bytes())),
+            Base64Url => value.from_base64().ok(),
+            Raw       => Some(value.as_bytes().to_vec())
+        }
+    }
+
+    /// True if `value` decodes to itself, so need not be decoded.
+    pub fn is_unescaped(&self, value: &str) -> bool {
+        match *self {
+            Percent   => !value.contains_char('%'),
+            Base64Url => false,
+            Raw       => true
+        }
+    }
+
+    /// Encode a cookie name.
+    pub fn encode_name(&self, name: &str) -> String {
+        match *self {
+            Raw => name.to_string(),
+            _   => percent_encode(name)
+        }
+    }
+
+    /// True if the cookie `name` decodes to itself, so need not be decoded.
+    pub fn is_unescaped_name(&self, name: &str) -> bool {
+        match *self {
+            Raw => true,
+            _   => !name.contains_char('%')
+        }
+    }
+
+    /// Decode a cookie name into its raw bytes.
+    pub fn decode_name(&self, name: &str) -> Vec<u8> {
+        match *self {
+            Raw => name.as_bytes().to_vec(),
+            _   => percent_decode(name.as_bytes())
+        }
+    }
+}
+
+/// A format for signed or encrypted values, such as those of other frameworks.
+///
+/// A `ValueCodec` given to the `CookieParser` replaces its own signatures:
+/// every cookie is decoded by the codec, and those it rejects are removed.
+/// It will also be used to set cookies through the parsed `Cookie`.
+///
+/// Codecs read and write values exactly as they appear in the HTTP headers,
+/// so they are responsible for any escaping their format requires.
+pub trait ValueCodec {
+    /// Encode `value` for the cookie `name`.
+    ///
+    /// Values prefixed with `j:` are JSON, as set with `res.set_json_cookie(...)`.
+    fn encode(&self, name: &str, value: &str) -> String;
+
+    /// Decode the value of the cookie `name`, returning `None` if it could not be verified.
+    ///
+    /// JSON values should be returned prefixed with `j:`, to be parsed into `Cookie.json`.
+    fn decode(&self, name: &str, value: &str) -> Option<String>;
+
+    /// Decode the value of the cookie `name`, as `decode`, or return why it could not be verified.
+    ///
+    /// The outcome is recorded in `Cookie.outcomes`. By default, any value which
+    /// cannot be decoded has an `InvalidSignature`; codecs may be more specific.
+    fn verify(&self, name: &str, value: &str) -> Result<String, Verification> {
+        self.decode(name, value).ok_or(InvalidSignature)
+    }
+}
+
+/// Serialize a value as JSON, for codecs which store JSON messages.
+///
+/// Values prefixed with `j:` are already JSON; others are stored as JSON strings.
+pub fn to_json_message(value: &str) -> String {
+    if value.starts_with("j:") {
+        value.slice_from(2).to_string()
+    } else {
+        json::String(value.to_string()).to_string()
+    }
+}
+
+/// Deserialize a JSON message, the reverse of `to_json_message`.
+///
+/// Returns `None` if the message is not JSON.
+pub fn from_json_message(message: String) -> Option<String> {
+    match json::from_str(message.as_slice()) {
+        Ok(json::String(value)) => Some(value),
+        Ok(_)                   => Some("j:".to_string().append(message.as_slice())),
+        Err(_)                  => None
+    }
+}
+
+fn base64_config() -> Config {
+    Config { pad: false, ..URL_SAFE }
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for &byte in value.as_bytes().iter() {
+        match byte as char {
+            'a'..'z' | 'A'..'Z' | '0'..'9' | '*' | '-' | '.' | '_' | '~' => encoded.push(byte as char),
+            _ => encoded.push_str(format!("%{:02X}", byte).as_slice())
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    fn roundtrip(encoding: Encoding, value: &String) -> bool {
+        match encoding.decode(encoding.encode(value.as_slice()).as_slice()) {
+            Some(bytes) => bytes.as_slice() == value.as_bytes(),
+            None        => false
+        }
+    }
+
+    #[test]
+    fn check_percent() {
+        assert_eq!(Percent.encode("a b+c"), "a%20b%2Bc".to_string());
+        assert_eq!(Percent.decode("a+b"), Some(b"a+b".to_vec()));
+        assert_eq!(Percent.encode("✓"), "%E2%9C%93".to_string());
+    }
+
+    #[test]
+    fn check_base64() {
+        assert_eq!(Base64Url.encode("a b?>"), "YSBiPz4".to_string());
+        assert_eq!(Base64Url.decode("YSBiPz4"), Some(b"a b?>".to_vec()));
+        assert_eq!(Base64Url.decode("not;base64"), None);
+        // Names are still percent-encoded
+        assert_eq!(Base64Url.encode_name("a b"), "a%20b".to_string());
+    }
+
+    #[test]
+    fn check_unescaped() {
+        assert!(Percent.is_unescaped("abc.def"));
+        assert!(!Percent.is_unescaped("a%20b"));
+        assert!(!Base64Url.is_unescaped("YSBi"));
+        assert!(Base64Url.is_unescaped_name("abc"));
+        assert!(Raw.is_unescaped("a%20b"));
+    }
+
+    #[test]
+    fn check_raw() {
+        assert_eq!(Raw.encode("a+b%20"), "a+b%20".to_string());
+        assert_eq!(Raw.decode("a+b%20"), Some(b"a+b%20".to_vec()));
+    }
+
+    #[test]
+    fn check_json_message() {
+        assert_eq!(to_json_message("j:[1,2]"), "[1,2]".to_string());
+        assert_eq!(from_json_message("[1,2]".to_string()), Some("j:[1,2]".to_string()));
+        // Found by fuzzing: the prefix check must not split a multibyte character
+        assert_eq!(to_json_message("a€b"), "\"a€b\"".to_string());
+        assert_eq!(from_json_message(to_json_message("a€b")), Some("a€b".to_string()));
+    }
+
+    #[test]
+    fn check_roundtrip_percent() {
+        fn prop(value: String) -> bool { roundtrip(Percent, &value) }
+        quickcheck(prop as fn(String) -> bool);
+    }
+
+    #[test]
+    fn check_roundtrip_base64() {
+        fn prop(value: String) -> bool { roundtrip(Base64Url, &value) }
+        quickcheck(prop as fn(String) -> bool);
+    }
+
+    #[test]
+    fn check_roundtrip_name() {
+        fn prop(name: String) -> bool {
+            Percent.decode_name(Percent.encode_name(name.as_slice()).as_slice()).as_slice() == name.as_bytes()
+        }
+        quickcheck(prop as fn(String) -> bool);
+    }
+}
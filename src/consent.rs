@@ -0,0 +1,458 @@
+//! Consent - keep cookies from being set without the user's consent
+
+use std::mem;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::collections::TreeMap;
+use serialize::json::{Json, Object, Boolean};
+use iron::{Request, Response, Middleware, Status, Continue};
+use time;
+use super::{Cookie, SetCookie, HeaderCollection, SetCookieError, Encoding, Percent};
+use store::parse_set_cookie;
+
+/// The purpose of a cookie, which users consent to by category.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ConsentCategory {
+    /// Cookies the site cannot work without, such as sessions. These are always set.
+    Essential,
+    /// Cookies measuring how the site is used.
+    Analytics,
+    /// Cookies for advertising, or for tracking users across sites.
+    Marketing
+}
+
+/// The categories a user has consented to, as kept in their consent cookie.
+///
+/// Set it as a JSON cookie, named as given to `ConsentPolicy::new`:
+/// `res.set_json_cookie(cookie, ("consent".to_string(), choice.to_json()), headers)`.
+#[deriving(Clone, PartialEq, Show)]
+pub struct ConsentChoice {
+    /// True if the user consented to analytics cookies.
+    pub analytics: bool,
+    /// True if the user consented to marketing cookies.
+    pub marketing: bool
+}
+
+impl ConsentChoice {
+    /// Consent to essential cookies only, as before the user has chosen.
+    pub fn essential() -> ConsentChoice {
+        ConsentChoice { analytics: false, marketing: false }
+    }
+
+    /// True if cookies of `category` may be set.
+    pub fn allows(&self, category: ConsentCategory) -> bool {
+        match category {
+            Essential => true,
+            Analytics => self.analytics,
+            Marketing => self.marketing
+        }
+    }
+
+    /// Read a choice from the JSON of a consent cookie.
+    ///
+    /// Categories missing from the object, or not `true`, are not consented to.
+    pub fn from_json(json: &Json) -> ConsentChoice {
+        ConsentChoice { analytics: consented(json, "analytics"), marketing: consented(json, "marketing") }
+    }
+
+    /// Write the choice as the JSON of a consent cookie.
+    pub fn to_json(&self) -> Json {
+        let mut object = TreeMap::new();
+        object.insert("analytics".to_string(), Boolean(self.analytics));
+        object.insert("marketing".to_string(), Boolean(self.marketing));
+        Object(object)
+    }
+}
+
+/// What a `Consent` did with a cookie.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ConsentOutcome {
+    /// The cookie was set.
+    Allowed,
+    /// The cookie was not set, as the user has not consented to its category.
+    Blocked(ConsentCategory),
+    /// The cookie was not set, as the user has not consented to its category,
+    /// but was kept by a queueing policy.
+    Queued(ConsentCategory),
+    /// The cookie was not set, as its name has no category.
+    Uncategorised
+}
+
+/// A cookie held back by a queueing `ConsentPolicy`.
+#[deriving(Clone, PartialEq, Show)]
+pub struct QueuedCookie {
+    /// The decoded cookie name.
+    pub name: String,
+    /// The Set-Cookie header, as it was to be sent.
+    pub header: String,
+    /// The category the user has not consented to.
+    pub category: ConsentCategory
+}
+
+impl QueuedCookie {
+    /// Set the cookie on `res`, as it was to be set, such as once the user consents.
+    pub fn set(self, res: &mut Response) {
+        res.headers.extensions.insert("Set-Cookie".to_string(), self.header);
+    }
+}
+
+/// Cookie consent enforcing `Middleware`.
+///
+/// This must be linked after a `CookieParser`, which is not `lazy`,
+/// to read the user's consent from their consent cookie, and before any
+/// middleware or handler setting cookies.
+///
+/// Every cookie must be categorised with `category`. On `exit`, any Set-Cookie header
+/// for a cookie of a category the user has not consented to, or of no category,
+/// is removed from the response, however it was set. Headers removing a cookie,
+/// with `Max-Age=0` or a past `Expires`, are kept. The consent cookie itself is essential.
+///
+/// It stores a `Consent` in the alloy, to report the cookies removed, and to
+/// learn whether a cookie will be set as it is set.
+#[deriving(Clone)]
+pub struct ConsentPolicy {
+    name: String,
+    categories: Arc<TreeMap<String, ConsentCategory>>,
+    queue: bool
+}
+
+impl ConsentPolicy {
+    /// Create a policy reading consent from the JSON cookie `name`.
+    pub fn new(name: &str) -> ConsentPolicy {
+        let mut categories = TreeMap::new();
+        categories.insert(name.to_string(), Essential);
+        ConsentPolicy { name: name.to_string(), categories: Arc::new(categories), queue: false }
+    }
+
+    /// Categorise the cookie `name`.
+    pub fn category(mut self, name: &str, category: ConsentCategory) -> ConsentPolicy {
+        let mut categories = (*self.categories).clone();
+        categories.insert(name.to_string(), category);
+        self.categories = Arc::new(categories);
+        self
+    }
+
+    /// Queue cookies the user has not consented to, rather than dropping them.
+    ///
+    /// Queued cookies are taken with `Consent::take_queued`, such as to set
+    /// once the user consents.
+    pub fn queue(mut self) -> ConsentPolicy {
+        self.queue = true;
+        self
+    }
+
+    /// Read the user's consent from a parsed cookie.
+    pub fn consent(&self, cookie: &Cookie) -> Consent {
+        let choice = match cookie.json.find(&self.name) {
+            Some(json) => ConsentChoice::from_json(json),
+            None       => ConsentChoice::essential()
+        };
+        let mut withdrawn: Vec<String> = Vec::new();
+        for &(ref name, _) in cookie.pairs.iter() {
+            match self.categories.find(name) {
+                Some(&category) if !choice.allows(category) && !withdrawn.contains(name) => withdrawn.push(name.clone()),
+                _                                                                         => ()
+            }
+        }
+        self.with_choice(choice, withdrawn, cookie.encoding.clone())
+    }
+
+    fn with_choice(&self, choice: ConsentChoice, withdrawn: Vec<String>, encoding: Encoding) -> Consent {
+        Consent {
+            policy: self.clone(),
+            encoding: encoding,
+            choice: choice,
+            withdrawn: withdrawn,
+            blocked: RefCell::new(Vec::new()),
+            queued: RefCell::new(Vec::new())
+        }
+    }
+}
+
+impl Middleware for ConsentPolicy {
+    /// Read the user's consent from the parsed `Cookie`, and store a `Consent` in the alloy.
+    ///
+    /// Without a parsed `Cookie`, only essential cookies are allowed.
+    fn enter(&mut self, req: &mut Request, _res: &mut Response) -> Status {
+        let consent = match req.alloy.find::<Cookie>() {
+            Some(cookie) => self.consent(cookie),
+            None         => self.with_choice(ConsentChoice::essential(), Vec::new(), Percent)
+        };
+        req.alloy.insert(consent);
+        Continue
+    }
+
+    /// Remove the response's Set-Cookie header, if the user has not consented to its cookie.
+    ///
+    /// The cookie is recorded in the `Consent`'s `blocked`, and queued by a queueing policy.
+    fn exit(&mut self, req: &mut Request, res: &mut Response) -> Status {
+        match req.alloy.find::<Consent>() {
+            Some(consent) => { consent.enforce(res); },
+            None          => ()
+        }
+        Continue
+    }
+}
+
+/// The user's consent to cookies, for one request.
+///
+/// This is the type stored in the alloy by `ConsentPolicy`.
+pub struct Consent {
+    policy: ConsentPolicy,
+    encoding: Encoding,
+    /// The categories the user has consented to.
+    pub choice: ConsentChoice,
+    /// The names of cookies received in categories the user has not consented to,
+    /// as when consent was withdrawn after they were set.
+    pub withdrawn: Vec<String>,
+    blocked: RefCell<Vec<(String, ConsentOutcome)>>,
+    queued: RefCell<Vec<QueuedCookie>>
+}
+
+impl Consent {
+    /// Set a cookie with `SetCookie`, and check it at once, as the `ConsentPolicy` will on `exit`.
+    ///
+    /// Cookies which are not set are recorded in `blocked`, and, for a queueing
+    /// policy, kept to be taken with `take_queued`. Any cookie already set on the response
+    /// is kept if this cookie is not set.
+    pub fn set_cookie(&self,
+                      res: &mut Response,
+                      signer: &Cookie,
+                      pair: (String, String),
+                      headers: HeaderCollection) -> Result<ConsentOutcome, SetCookieError> {
+        let previous = res.headers.extensions.find(&"Set-Cookie".to_string()).map(|header| header.clone());
+        try!(res.set_cookie(signer, pair, headers));
+        Ok(self.check_set(res, previous))
+    }
+
+    /// Set a cookie as JSON with `SetCookie`, if the user has consented to its category.
+    pub fn set_json_cookie(&self,
+                           res: &mut Response,
+                           signer: &Cookie,
+                           pair: (String, Json),
+                           headers: HeaderCollection) -> Result<ConsentOutcome, SetCookieError> {
+        let previous = res.headers.extensions.find(&"Set-Cookie".to_string()).map(|header| header.clone());
+        try!(res.set_json_cookie(signer, pair, headers));
+        Ok(self.check_set(res, previous))
+    }
+
+    // Check the cookie just set on `res`, restoring the `previous` header if it is not allowed
+    fn check_set(&self, res: &mut Response, previous: Option<String>) -> ConsentOutcome {
+        let outcome = self.enforce(res).unwrap_or(Allowed);
+        match previous {
+            Some(header) if outcome != Allowed => { res.headers.extensions.insert("Set-Cookie".to_string(), header); },
+            _                                  => ()
+        }
+        outcome
+    }
+
+    // Remove the Set-Cookie header of `res` if the user has not consented to its cookie
+    fn enforce(&self, res: &mut Response) -> Option<ConsentOutcome> {
+        let header = match res.headers.extensions.find(&"Set-Cookie".to_string()) {
+            Some(header) => header.clone(),
+            None         => return None
+        };
+        let now = time::get_time().sec;
+        let cookie = parse_set_cookie(header.as_slice(), now);
+        // Removing a cookie, as `remove_withdrawn` does, needs no consent, whatever its value
+        if cookie.as_ref().and_then(|cookie| cookie.expires).map_or(false, |expires| expires <= now) {
+            return Some(Allowed);
+        }
+        let name = match cookie {
+            Some(cookie) if self.encoding.is_unescaped_name(cookie.name.as_slice()) => cookie.name,
+            Some(cookie) => String::from_utf8_lossy(self.encoding.decode_name(cookie.name.as_slice()).as_slice()).into_string(),
+            None         => String::new()
+        };
+        let outcome = match self.policy.categories.find(&name) {
+            Some(&category) if self.choice.allows(category) => return Some(Allowed),
+            Some(&category) if self.policy.queue             => Queued(category),
+            Some(&category)                                  => Blocked(category),
+            None                                             => Uncategorised
+        };
+        res.headers.extensions.remove(&"Set-Cookie".to_string());
+        self.blocked.borrow_mut().push((name.clone(), outcome.clone()));
+        match outcome {
+            Queued(category) => self.queued.borrow_mut().push(QueuedCookie { name: name, header: header, category: category }),
+            _                => ()
+        }
+        Some(outcome)
+    }
+
+    /// Every cookie which was not set, in order, with the reason.
+    pub fn blocked(&self) -> Vec<(String, ConsentOutcome)> {
+        self.blocked.borrow().clone()
+    }
+
+    /// Take every cookie queued by a queueing policy, in order.
+    pub fn take_queued(&self) -> Vec<QueuedCookie> {
+        mem::replace(&mut *self.queued.borrow_mut(), Vec::new())
+    }
+
+    /// Remove a `withdrawn` cookie from the browser, by setting it to expire.
+    ///
+    /// Only one cookie may be set per response, so this removes the first, returning its name.
+    /// The rest are removed by following responses, as the browser stops sending each.
+    /// Cookies are removed from `Path=/`, and as `Secure` if their name is prefixed.
+    pub fn remove_withdrawn(&self, res: &mut Response, signer: &Cookie) -> Result<Option<String>, SetCookieError> {
+        let name = match self.withdrawn.as_slice().get(0) {
+            Some(name) => name.clone(),
+            None       => return Ok(None)
+        };
+        let mut headers = HeaderCollection::aged(0);
+        headers.expires = Some(time::at_utc(time::Timespec::new(0, 0)));
+        headers.path = Some("/".to_string());
+        headers.secure = name.as_slice().starts_with("__Secure-") || name.as_slice().starts_with("__Host-");
+        try!(res.set_cookie(signer, (name.clone(), String::new()), headers));
+        Ok(Some(name))
+    }
+}
+
+fn consented(json: &Json, category: &str) -> bool {
+    json.find(&category.to_string()).and_then(|consent| consent.as_boolean()).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use iron::{Request, Response, Middleware};
+    use test::mock::{request, response};
+    use super::*;
+    use super::super::{Cookie, CookieParser, SetCookie, HeaderCollection};
+
+    fn policy() -> ConsentPolicy {
+        ConsentPolicy::new("consent")
+            .category("sid", Essential)
+            .category("_ga", Analytics)
+            .category("ad", Marketing)
+    }
+
+    fn consent_header(choice: &ConsentChoice) -> String {
+        let mut res = response::new();
+        res.set_json_cookie(&Cookie::new(None), ("consent".to_string(), choice.to_json()), HeaderCollection::empty()).unwrap();
+        let header = res.headers.extensions.find(&"Set-Cookie".to_string()).unwrap();
+        header.as_slice().split(';').next().unwrap().to_string()
+    }
+
+    fn request_with(parser: &mut CookieParser, policy: &mut ConsentPolicy, header: &str) -> Request {
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        req.headers.extensions.insert("Cookie".to_string(), header.to_string());
+        parser.enter(&mut req, &mut response::new());
+        policy.enter(&mut req, &mut response::new());
+        req
+    }
+
+    fn set_cookie(res: &Response) -> Option<String> {
+        res.headers.extensions.find(&"Set-Cookie".to_string()).map(|header| header.clone())
+    }
+
+    #[test]
+    fn check_choice() {
+        let choice = ConsentChoice { analytics: true, marketing: false };
+        assert_eq!(ConsentChoice::from_json(&choice.to_json()), choice);
+        assert!(choice.allows(Essential) && choice.allows(Analytics) && !choice.allows(Marketing));
+        assert!(!ConsentChoice::essential().allows(Analytics));
+    }
+
+    #[test]
+    fn check_set_cookie() {
+        let header = consent_header(&ConsentChoice { analytics: true, marketing: false });
+        let req = request_with(&mut CookieParser::new(), &mut policy(), header.as_slice());
+        let consent = req.alloy.find::<Consent>().unwrap();
+        let cookie = req.alloy.find::<Cookie>().unwrap();
+
+        let mut res = response::new();
+        assert_eq!(consent.set_cookie(&mut res, cookie, ("_ga".to_string(), "1".to_string()), HeaderCollection::empty()),
+                   Ok(Allowed));
+        assert_eq!(set_cookie(&res), Some("_ga=1".to_string()));
+
+        let mut res = response::new();
+        assert_eq!(consent.set_cookie(&mut res, cookie, ("ad".to_string(), "1".to_string()), HeaderCollection::empty()),
+                   Ok(Blocked(Marketing)));
+        assert_eq!(consent.set_cookie(&mut res, cookie, ("other".to_string(), "1".to_string()), HeaderCollection::empty()),
+                   Ok(Uncategorised));
+        assert_eq!(set_cookie(&res), None);
+        assert_eq!(consent.blocked(), vec![("ad".to_string(), Blocked(Marketing)), ("other".to_string(), Uncategorised)]);
+    }
+
+    #[test]
+    fn check_queue() {
+        // Without a consent cookie, only essential cookies are set
+        let req = request_with(&mut CookieParser::new(), &mut policy().queue(), "sid=abc");
+        let consent = req.alloy.find::<Consent>().unwrap();
+        let cookie = req.alloy.find::<Cookie>().unwrap();
+        assert_eq!(consent.choice, ConsentChoice::essential());
+
+        let mut res = response::new();
+        assert_eq!(consent.set_cookie(&mut res, cookie, ("_ga".to_string(), "1".to_string()), HeaderCollection::empty()),
+                   Ok(Queued(Analytics)));
+        assert_eq!(set_cookie(&res), None);
+        let queued = consent.take_queued();
+        assert_eq!((queued[0].name.as_slice(), queued[0].header.as_slice(), queued[0].category), ("_ga", "_ga=1", Analytics));
+        assert!(consent.take_queued().is_empty());
+        queued[0].clone().set(&mut res);
+        assert_eq!(set_cookie(&res), Some("_ga=1".to_string()));
+        assert_eq!(consent.set_cookie(&mut res, cookie, ("sid".to_string(), "abc".to_string()), HeaderCollection::empty()),
+                   Ok(Allowed));
+    }
+
+    #[test]
+    fn check_withdrawn() {
+        let header = consent_header(&ConsentChoice::essential()).append("; _ga=1; sid=abc; ad=2; _ga=3");
+        let req = request_with(&mut CookieParser::new(), &mut policy(), header.as_slice());
+        let consent = req.alloy.find::<Consent>().unwrap();
+        let cookie = req.alloy.find::<Cookie>().unwrap();
+        assert_eq!(consent.withdrawn, vec!["_ga".to_string(), "ad".to_string()]);
+
+        let mut res = response::new();
+        assert_eq!(consent.remove_withdrawn(&mut res, cookie), Ok(Some("_ga".to_string())));
+        assert_eq!(set_cookie(&res), Some("_ga=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0; Path=/".to_string()));
+
+        let req = request_with(&mut CookieParser::new(), &mut policy(), "sid=abc");
+        let consent = req.alloy.find::<Consent>().unwrap();
+        assert_eq!(consent.remove_withdrawn(&mut response::new(), req.alloy.find::<Cookie>().unwrap()), Ok(None));
+    }
+
+    #[test]
+    fn check_withdrawn_signed() {
+        let signer = Cookie::new(Some("@zzmp".to_string()));
+        let header = format!("_ga=s:1.{}", signer.sign("_ga", &"1".to_string()).unwrap());
+        let mut policy = policy();
+        let mut req = request_with(&mut CookieParser::signed_unchecked("@zzmp".to_string()), &mut policy, header.as_slice());
+        let mut res = response::new();
+        {
+            let consent = req.alloy.find::<Consent>().unwrap();
+            assert_eq!(consent.withdrawn, vec!["_ga".to_string()]);
+            assert_eq!(consent.remove_withdrawn(&mut res, req.alloy.find::<Cookie>().unwrap()), Ok(Some("_ga".to_string())));
+        }
+        // The removal is signed, so its value is not empty, but it is kept on exit
+        let removal = set_cookie(&res).unwrap();
+        assert!(removal.as_slice().starts_with("_ga=s:."));
+        policy.exit(&mut req, &mut res);
+        assert_eq!(set_cookie(&res), Some(removal));
+        assert!(req.alloy.find::<Consent>().unwrap().blocked().is_empty());
+    }
+
+    #[test]
+    fn check_exit() {
+        let header = consent_header(&ConsentChoice { analytics: true, marketing: false });
+        let mut policy = policy();
+        let mut req = request_with(&mut CookieParser::new(), &mut policy, header.as_slice());
+
+        // Cookies set without `Consent` are checked as the response leaves the policy
+        let mut res = response::new();
+        res.set_cookie(req.alloy.find::<Cookie>().unwrap(), ("ad".to_string(), "1".to_string()), HeaderCollection::empty()).unwrap();
+        policy.exit(&mut req, &mut res);
+        assert_eq!(set_cookie(&res), None);
+
+        let mut res = response::new();
+        res.set_cookie(req.alloy.find::<Cookie>().unwrap(), ("_ga".to_string(), "1".to_string()), HeaderCollection::empty()).unwrap();
+        policy.exit(&mut req, &mut res);
+        assert_eq!(set_cookie(&res), Some("_ga=1".to_string()));
+
+        let mut res = response::new();
+        res.headers.extensions.insert("Set-Cookie".to_string(), "other=1; Path=/".to_string());
+        policy.exit(&mut req, &mut res);
+        assert_eq!(set_cookie(&res), None);
+        assert_eq!(req.alloy.find::<Consent>().unwrap().blocked(),
+                   vec![("ad".to_string(), Blocked(Marketing)), ("other".to_string(), Uncategorised)]);
+    }
+}
@@ -1,23 +1,62 @@
 //! Parsing functionality - get cookie data
 
+use std::fmt;
+use std::sync::Arc;
 use std::collections::hashmap::HashMap;
 use serialize::json::{Json, Null};
 use serialize::hex::ToHex;
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
-use crypto::mac::Mac;
-use crypto::hmac::Hmac;
+use serialize::base64::{ToBase64, STANDARD, Config};
+use crypto::util::fixed_time_eq;
+use super::{Rejection, Verification, Verified, Encoding, Percent, ValueCodec, Signer, HmacSha256, MasterKey};
+use keys::SecretKeys;
+
+// The tag of untagged signatures, as written before signatures were tagged
+static UNTAGGED: &'static str = "sha256";
 
 /// The parsed cookie.
 ///
 /// This is the type stored in the alloy.
-#[deriving(Show)]
 pub struct Cookie {
     /// True to set/get signed cookies only
     pub signed: bool,
-    secret: Option<String>,
+    keys: Arc<SecretKeys>,
+    /// The encoding of cookie names and values.
+    ///
+    /// This is set by the `CookieParser`, and used to set cookies.
+    pub encoding: Encoding,
+    /// The text format of cookie signatures.
+    ///
+    /// This is set by the `CookieParser`, and used to set cookies.
+    pub signature_format: SignatureFormat,
+    /// The MAC used to sign cookies.
+    ///
+    /// This is set by the `CookieParser`, and used to set cookies.
+    pub signer: Arc<Box<Signer + Send + Sync>>,
+    /// Further MACs whose signatures are accepted, such as one being replaced.
+    ///
+    /// This is set by the `CookieParser`.
+    pub accepted: Vec<Arc<Box<Signer + Send + Sync>>>,
+    /// A codec for signed or encrypted values, replacing our own signatures.
+    ///
+    /// This is set by the `CookieParser`, and used to set cookies.
+    pub codec: Option<Arc<Box<ValueCodec + Send + Sync>>>,
     /// The parsed RFC 6265-styled cookies.
+    ///
+    /// Names received more than once are resolved by the parser's `DuplicatePolicy`.
     pub map: HashMap<String, String>,
+    /// Every parsed cookie, in the order it was received.
+    ///
+    /// Unlike `map`, this keeps every occurrence of a name.
+    /// JSON cookies are included in their serialized, `j:`-prefixed form.
+    pub pairs: Vec<(String, String)>,
+    /// The raw names of any cookies rejected by a `Strict` parser,
+    /// or for exceeding its `max_size`, with the reason each was rejected.
+    pub rejected: Vec<(String, Rejection)>,
+    /// The decoded name of every other cookie received, in order,
+    /// with the outcome of verifying its signature.
+    ///
+    /// This includes cookies removed for a missing or invalid signature.
+    pub outcomes: Vec<(String, Verification)>,
     /// Any JSON fields, parsed into a single object
     ///
     /// JSON stored under key `myJson` will be available
@@ -25,31 +64,139 @@ pub struct Cookie {
     pub json: Json
 }
 
+/// The text format of cookie signatures.
+///
+/// Signed cookies are written as `s:value.signature`, where the signature
+/// is prefixed with the tag of its `Signer`, as `sha512$signature`.
+/// HMAC SHA-256 signatures are untagged.
+#[deriving(Clone, PartialEq, Show)]
+pub enum SignatureFormat {
+    /// Lowercase hex. This is the default.
+    HexSignature,
+    /// Base64 without padding, as written by Express' `cookie-signature`.
+    ///
+    /// Cookies signed this way with HMAC SHA-256, the default signer,
+    /// can be read by Express' `cookie-parser` with the same secret, and vice versa.
+    ExpressSignature
+}
+
 impl Cookie {
     /// Create a new cookie
     pub fn new(secret: Option<String>) -> Cookie {
-        Cookie {
-            signed: secret.is_some(),
-            secret: secret,
-            map: HashMap::new(),
-            json: Null
+        let signer = Arc::new(box HmacSha256 as Box<Signer + Send + Sync>);
+        let keys = SecretKeys::new(secret.as_ref().map(|secret| secret.as_slice())).precompute(&[&signer]);
+        keyed_cookie(Arc::new(keys), signer)
+    }
+
+    /// Create a new cookie, signed with keys derived from `master`
+    /// for each cookie name.
+    pub fn derived(master: MasterKey) -> Cookie {
+        let signer = Arc::new(box HmacSha256 as Box<Signer + Send + Sync>);
+        keyed_cookie(Arc::new(SecretKeys::derived(master)), signer)
+    }
+
+    /// Find the value of the cookie `name`.
+    ///
+    /// If `name` was received more than once, the value is chosen
+    /// by the parser's `DuplicatePolicy`.
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.map.find_equiv(&name)
+    }
+
+    /// Find every value received for the cookie `name`, in the order received.
+    pub fn get_all(&self, name: &str) -> Vec<&String> {
+        self.pairs
+            .iter()
+            .filter(|&&(ref key, _)| key.as_slice() == name)
+            .map(|&(_, ref val)| val)
+            .collect()
+    }
+
+    /// Find the outcome of verifying the signature of the cookie `name`.
+    ///
+    /// If `name` was received more than once, this is `Verified` if any
+    /// occurrence was verified, or otherwise the outcome of the first.
+    /// Returns `None` if no such cookie was received, or it was rejected.
+    pub fn verification(&self, name: &str) -> Option<Verification> {
+        let outcomes: Vec<&Verification> = self.outcomes
+            .iter()
+            .filter(|&&(ref key, _)| key.as_slice() == name)
+            .map(|&(_, ref outcome)| outcome)
+            .collect();
+        if outcomes.contains(&&Verified) {
+            Some(Verified)
+        } else {
+            outcomes.head().map(|&outcome| outcome.clone())
         }
     }
 
     /// Encode your signature
     ///
-    /// Signatures will be authenticated with HMAC SHA-256.
-    pub fn sign(&self, value: &String) -> Option<String> {
-        match self.secret {
-            Some(ref secret) => {
-                let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
-                hmac.input(value.as_bytes());
-
-                let hash: &mut [u8] = [0, ..32];
-                hmac.raw_result(hash);
-                Some(hash.as_slice().to_hex())
-            },
-            None             => None
+    /// Signatures will be authenticated by the `signer`, written in the
+    /// `signature_format`, and tagged with the signer's tag.
+    /// With a `MasterKey`, each cookie `name` is signed with its own key.
+    pub fn sign(&self, name: &str, value: &String) -> Option<String> {
+        self.keys.sign(&self.signer, name, value.as_bytes()).map(|mac| {
+            let signature = self.format_signature(mac);
+            let tag = self.signer.tag();
+            if tag == UNTAGGED {
+                signature
+            } else {
+                tag.to_string().append("$").append(signature.as_slice())
+            }
+        })
+    }
+
+    /// Verify a signature of `value`, as produced by `sign`.
+    ///
+    /// Signatures by the `signer` or any `accepted` signer are valid.
+    /// Untagged signatures are HMAC SHA-256.
+    pub fn verify(&self, name: &str, value: &String, signature: &str) -> bool {
+        let (tag, signature) = match signature.find('$') {
+            Some(i) => (signature.slice_to(i), signature.slice_from(i + 1)),
+            None    => (UNTAGGED, signature)
+        };
+        let signer = if self.signer.tag() == tag {
+            Some(&self.signer)
+        } else {
+            self.accepted.iter().find(|signer| signer.tag() == tag)
+        };
+        match signer.and_then(|signer| self.keys.sign(signer, name, value.as_bytes())) {
+            Some(mac) => fixed_time_eq(self.format_signature(mac).as_bytes(), signature.as_bytes()),
+            None      => false
         }
     }
+
+    fn format_signature(&self, mac: Vec<u8>) -> String {
+        match self.signature_format {
+            HexSignature     => mac.as_slice().to_hex(),
+            ExpressSignature => mac.as_slice().to_base64(Config { pad: false, ..STANDARD })
+        }
+    }
+}
+
+// Create a new cookie, signing with `keys` shared by the parser
+pub fn keyed_cookie(keys: Arc<SecretKeys>, signer: Arc<Box<Signer + Send + Sync>>) -> Cookie {
+    Cookie {
+        signed: keys.is_signed(),
+        keys: keys,
+        encoding: Percent,
+        signature_format: HexSignature,
+        signer: signer,
+        accepted: Vec::new(),
+        codec: None,
+        map: HashMap::new(),
+        pairs: Vec::new(),
+        rejected: Vec::new(),
+        outcomes: Vec::new(),
+        json: Null
+    }
+}
+
+impl fmt::Show for Cookie {
+    // Secrets, signers and codecs are left out
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cookie {{ signed: {}, encoding: {}, map: {}, pairs: {}, rejected: {}, outcomes: {}, json: {} }}",
+               self.signed, self.encoding, self.map, self.pairs, self.rejected, self.outcomes, self.json)
+    }
 }
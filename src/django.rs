@@ -0,0 +1,249 @@
+//! Django compatibility - share signed cookies with Django
+
+use std::char;
+use std::num::from_str_radix;
+use serialize::base64::{ToBase64, URL_SAFE, Config};
+use crypto::util::fixed_time_eq;
+use time;
+use super::{ValueCodec, HmacDigest, HmacSha256};
+use super::{Verification, InvalidSignature, Malformed, Expired};
+
+static BASE62_ALPHABET: &'static str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// A `ValueCodec` for cookies set by Django, through `response.set_signed_cookie`.
+///
+/// Values are signed by `django.core.signing.TimestampSigner`, as `value:timestamp:signature`,
+/// with the key and salt conventions of `get_cookie_signer`.
+/// Values are quoted as Python's `SimpleCookie` quotes them.
+#[deriving(Clone)]
+pub struct DjangoCodec {
+    key: Vec<u8>,
+    salt: String,
+    digest: HmacDigest,
+    max_age: Option<i64>
+}
+
+impl DjangoCodec {
+    /// Read and write cookies signed with Django's `SECRET_KEY`, with HMAC SHA-256.
+    ///
+    /// SHA-256 is the default algorithm of Django 3.1 and later.
+    pub fn new(secret_key: &str) -> DjangoCodec {
+        DjangoCodec {
+            key: b"django.http.cookies".to_vec().append(secret_key.as_bytes()),
+            salt: String::new(),
+            digest: HmacSha256,
+            max_age: None
+        }
+    }
+
+    /// Set the `salt` given to `set_signed_cookie` and `get_signed_cookie`.
+    pub fn salt(mut self, salt: &str) -> DjangoCodec {
+        self.salt = salt.to_string();
+        self
+    }
+
+    /// Set the signing algorithm.
+    ///
+    /// Use `HmacSha1` for cookies signed before Django 3.1.
+    pub fn digest(mut self, digest: HmacDigest) -> DjangoCodec {
+        self.digest = digest;
+        self
+    }
+
+    /// Reject cookies signed more than `seconds` ago,
+    /// as with the `max_age` of `get_signed_cookie`.
+    pub fn max_age(mut self, seconds: i64) -> DjangoCodec {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    // `Signer.signature`, salted with the cookie name as by `get_cookie_signer`
+    fn signature(&self, name: &str, value: &str) -> String {
+        // `salted_hmac`
+        let key_salt = name.to_string().append(self.salt.as_slice()).append("signer");
+        let key = self.digest.hash(key_salt.into_bytes().append(self.key.as_slice()).as_slice());
+        self.digest.hmac(key.as_slice(), value.as_bytes()).as_slice().to_base64(base64_config())
+    }
+}
+
+impl ValueCodec for DjangoCodec {
+    fn encode(&self, name: &str, value: &str) -> String {
+        let value = value.to_string().append(":").append(b62_encode(time::get_time().sec).as_slice());
+        let signature = self.signature(name, value.as_slice());
+        quote(value.append(":").append(signature.as_slice()).as_slice())
+    }
+
+    fn decode(&self, name: &str, value: &str) -> Option<String> {
+        self.verify(name, value).ok()
+    }
+
+    fn verify(&self, name: &str, value: &str) -> Result<String, Verification> {
+        let value = unquote(value);
+        let (value, signature) = try!(rsplit(value.as_slice()).ok_or(Malformed));
+        let expected = self.signature(name, value);
+        if !fixed_time_eq(signature.as_bytes(), expected.as_bytes()) { return Err(InvalidSignature) }
+
+        let (value, timestamp) = try!(rsplit(value).ok_or(Malformed));
+        match (self.max_age, b62_decode(timestamp)) {
+            (Some(max_age), Some(timestamp)) if time::get_time().sec - timestamp > max_age => Err(Expired),
+            (_, Some(_))                                                                  => Ok(value.to_string()),
+            (_, None)                                                                     => Err(Malformed)
+        }
+    }
+}
+
+fn base64_config() -> Config {
+    Config { pad: false, ..URL_SAFE }
+}
+
+fn rsplit<'a>(value: &'a str) -> Option<(&'a str, &'a str)> {
+    value.rfind(':').map(|i| (value.slice_to(i), value.slice_from(i + 1)))
+}
+
+fn b62_encode(mut value: i64) -> String {
+    if value == 0 { return "0".to_string() }
+    let sign = if value < 0 { value = -value; "-" } else { "" };
+    let mut digits = Vec::new();
+    while value > 0 {
+        digits.push(BASE62_ALPHABET.char_at((value % 62) as uint));
+        value = value / 62;
+    }
+    digits.reverse();
+    sign.to_string().append(String::from_chars(digits.as_slice()).as_slice())
+}
+
+fn b62_decode(value: &str) -> Option<i64> {
+    let (sign, digits) = if value.starts_with("-") { (-1, value.slice_from(1)) } else { (1, value) };
+    if digits.is_empty() { return None }
+    let mut decoded = 0i64;
+    for c in digits.chars() {
+        match BASE62_ALPHABET.find(c) {
+            Some(digit) => decoded = decoded * 62 + digit as i64,
+            None        => return None
+        }
+    }
+    Some(sign * decoded)
+}
+
+// `http.cookies._LegalChars`
+fn is_legal(c: char) -> bool {
+    match c {
+        'a'..'z' | 'A'..'Z' | '0'..'9' => true,
+        '!'|'#'|'$'|'%'|'&'|'\''|'*'|'+'|'-'|'.'|'^'|'_'|'`'|'|'|'~'|':' => true,
+        _ => false
+    }
+}
+
+// Quote a value as `http.cookies._quote` does
+fn quote(value: &str) -> String {
+    if value.chars().all(is_legal) { return value.to_string() }
+    let mut quoted = "\"".to_string();
+    for c in value.chars() {
+        match c {
+            '"'  => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            ' '|'('|')'|'/'|'<'|'='|'>'|'?'|'@'|'['|']'|'{'|'}' => quoted.push(c),
+            c if !is_legal(c) && (c as u32) < 256 => quoted.push_str(format!("\\{:03o}", c as u32).as_slice()),
+            c => quoted.push(c)
+        }
+    }
+    quoted.append("\"")
+}
+
+// Unescape a value quoted by `quote`, whose DQUOTEs have been removed by the parser,
+// as `http.cookies._unquote` does
+fn unquote(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let mut unquoted = String::with_capacity(value.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '\\' && i + 3 < chars.len() && chars.slice(i + 1, i + 4).iter().all(|c| c.is_digit_radix(8)) {
+            let octal = String::from_chars(chars.slice(i + 1, i + 4));
+            match from_str_radix::<u32>(octal.as_slice(), 8).and_then(char::from_u32) {
+                Some(c) => unquoted.push(c),
+                None    => unquoted.push_str(octal.as_slice())
+            }
+            i += 4;
+        } else if chars[i] == '\\' && i + 1 < chars.len() {
+            unquoted.push(chars[i + 1]);
+            i += 2;
+        } else {
+            unquoted.push(chars[i]);
+            i += 1;
+        }
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{quote, unquote, b62_encode, b62_decode};
+    use super::super::codec::ValueCodec;
+    use super::super::mac::HmacSha1;
+    use super::super::parser::{Expired, InvalidSignature};
+
+    // Test vectors follow `django.core.signing`, with this `SECRET_KEY`,
+    // signed at timestamp 1400000000
+    static SECRET_KEY: &'static str = "iron secret";
+
+    #[test]
+    fn check_signed() {
+        // response.set_signed_cookie("color", "blue")
+        let codec = DjangoCodec::new(SECRET_KEY);
+        let value = "blue:1WkFxA:2B_y7l58chPgBQ6oY1OZVCqpIcF0cqBx8uwt68DOmTs";
+        assert_eq!(codec.decode("color", value), Some("blue".to_string()));
+        // The cookie name salts the signature
+        assert_eq!(codec.decode("colour", value), None);
+        // Signed too long ago
+        assert_eq!(codec.clone().max_age(60).decode("color", value), None);
+        assert_eq!(codec.clone().max_age(60).verify("color", value), Err(Expired));
+        assert_eq!(codec.verify("colour", value), Err(InvalidSignature));
+    }
+
+    #[test]
+    fn check_salt() {
+        // response.set_signed_cookie("color", "blue", salt="prefs")
+        let value = "blue:1WkFxA:aD0GT-rfEWrlrUDmu02e8WyXy7tvtgkuH0OJVC-qxNE";
+        assert_eq!(DjangoCodec::new(SECRET_KEY).salt("prefs").decode("color", value), Some("blue".to_string()));
+        assert_eq!(DjangoCodec::new(SECRET_KEY).decode("color", value), None);
+    }
+
+    #[test]
+    fn check_sha1() {
+        // As signed before Django 3.1
+        let value = "blue:1WkFxA:qcFROpcx3iVNlKppmWeeKn8Zskg";
+        assert_eq!(DjangoCodec::new(SECRET_KEY).digest(HmacSha1).decode("color", value), Some("blue".to_string()));
+    }
+
+    #[test]
+    fn check_roundtrip() {
+        let codec = DjangoCodec::new(SECRET_KEY).max_age(60);
+        for &value in ["blue", "a b,c;d\"e\\f", "caf\u00e9", "\u2713", ""].iter() {
+            let encoded = codec.encode("color", value);
+            // The parser removes any DQUOTEs
+            let encoded = if encoded.as_slice().starts_with("\"") {
+                encoded.as_slice().slice(1, encoded.len() - 1).to_string()
+            } else {
+                encoded
+            };
+            assert_eq!(codec.decode("color", encoded.as_slice()), Some(value.to_string()));
+        }
+    }
+
+    #[test]
+    fn check_quote() {
+        assert_eq!(quote("blue:1WkFxA"), "blue:1WkFxA".to_string());
+        assert_eq!(quote("a b,c;\"\u00e9"), "\"a b\\054c\\073\\\"\\351\"".to_string());
+        assert_eq!(unquote("a b\\054c\\073\\\"\\351"), "a b,c;\"\u00e9".to_string());
+    }
+
+    #[test]
+    fn check_b62() {
+        assert_eq!(b62_encode(1400000000), "1WkFxA".to_string());
+        assert_eq!(b62_decode("1WkFxA"), Some(1400000000));
+        assert_eq!(b62_encode(0), "0".to_string());
+        assert_eq!(b62_decode("-z"), Some(-61));
+        assert_eq!(b62_decode("!"), None);
+    }
+}
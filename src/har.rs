@@ -0,0 +1,363 @@
+//! HAR - import and export cookies of recorded browser sessions
+//!
+//! Cookies are read from the entries of a [HAR 1.2](http://www.softwareishard.com/blog/har-12-spec/) log,
+//! as recorded by browsers' developer tools.
+
+use std::collections::TreeMap;
+use std::ascii::AsciiExt;
+use time;
+use url::Url;
+use serialize::json;
+use serialize::json::{Json, Object, List, Boolean};
+use store::{CookieStore, StoredCookie, parse_set_cookie, format_date};
+use parser::parse_pair;
+
+/// A cookie as recorded in a HAR file.
+#[deriving(Clone, PartialEq, Show)]
+pub struct HarCookie {
+    /// The cookie name.
+    pub name: String,
+    /// The cookie value, as sent or set.
+    pub value: String,
+    /// The `Path` attribute.
+    pub path: Option<String>,
+    /// The `Domain` attribute.
+    pub domain: Option<String>,
+    /// The `Expires` attribute, in seconds since the Unix epoch.
+    pub expires: Option<i64>,
+    /// True if the cookie is `HttpOnly`.
+    pub http_only: bool,
+    /// True if the cookie is `Secure`.
+    pub secure: bool
+}
+
+/// A request and its response, as recorded in a HAR file.
+#[deriving(Clone, PartialEq, Show)]
+pub struct HarEntry {
+    /// The URL requested.
+    pub url: String,
+    /// The cookies sent with the request.
+    pub request_cookies: Vec<HarCookie>,
+    /// The cookies set by the response.
+    pub response_cookies: Vec<HarCookie>
+}
+
+/// The reasons a HAR file could not be read.
+#[deriving(Clone, PartialEq, Show)]
+pub enum HarError {
+    /// The file is not valid JSON.
+    HarSyntaxError,
+    /// A field required by the HAR format is missing, or of the wrong type.
+    HarMissingField(&'static str)
+}
+
+/// Read the entries of a HAR file, in the order recorded.
+///
+/// Cookies missing from an entry's `cookies` are read from its `Cookie`
+/// and `Set-Cookie` headers, as some browsers record only those. These are
+/// read as `CookieParser` and `CookieStore` read them, with `Max-Age` counted from now.
+pub fn read_har(text: &str) -> Result<Vec<HarEntry>, HarError> {
+    let har = try!(json::from_str(text).map_err(|_| HarSyntaxError));
+    let log = try!(field(&har, "log"));
+    let entries = try!(field(log, "entries").and_then(|entries| entries.as_list().ok_or(HarMissingField("entries"))));
+    let now = time::get_time().sec;
+    entries.iter().map(|entry| {
+        let request = try!(field(entry, "request"));
+        let response = try!(field(entry, "response"));
+        let url = try!(string_field(request, "url"));
+        let mut request_cookies = try!(cookies(request));
+        if request_cookies.is_empty() {
+            for header in try!(headers(request, "cookie")).iter() {
+                request_cookies.extend(header.as_slice().split(';').filter_map(parse_pair).map(request_cookie));
+            }
+        }
+        let mut response_cookies = try!(cookies(response));
+        if response_cookies.is_empty() {
+            response_cookies = try!(headers(response, "set-cookie")).iter()
+                .filter_map(|header| response_cookie(header.as_slice(), now))
+                .collect();
+        }
+        Ok(HarEntry { url: url, request_cookies: request_cookies, response_cookies: response_cookies })
+    }).collect()
+}
+
+/// Replay the cookies set by each response, in order, into a new store.
+///
+/// Cookies are stored as if received now, from each entry's URL.
+pub fn store_from_har(entries: &[HarEntry]) -> CookieStore {
+    let mut store = CookieStore::new();
+    for entry in entries.iter() {
+        let url = match Url::parse(entry.url.as_slice()) {
+            Ok(url) => url,
+            Err(_)  => continue
+        };
+        for cookie in entry.response_cookies.iter() {
+            store.set_cookie(&url, cookie.to_set_cookie().as_slice());
+        }
+    }
+    store
+}
+
+/// Write every cookie in `store` as a list of HAR cookie objects.
+pub fn har_cookies(store: &CookieStore) -> Json {
+    List(store.cookies().iter().map(|cookie| HarCookie::from_stored(cookie).to_json()).collect())
+}
+
+impl HarEntry {
+    /// The Cookie header sent with the request, to replay it through a `CookieParser`.
+    pub fn cookie_header(&self) -> Option<String> {
+        if self.request_cookies.is_empty() { return None }
+        let pairs: Vec<String> = self.request_cookies.iter()
+            .map(|cookie| cookie.name.clone().append("=").append(cookie.value.as_slice()))
+            .collect();
+        Some(pairs.connect("; "))
+    }
+}
+
+impl HarCookie {
+    /// Read a HAR cookie object.
+    pub fn from_json(json: &Json) -> Result<HarCookie, HarError> {
+        Ok(HarCookie {
+            name: try!(string_field(json, "name")),
+            value: try!(string_field(json, "value")),
+            path: optional(json, "path"),
+            domain: optional(json, "domain"),
+            expires: optional(json, "expires").and_then(|expires| parse_iso8601(expires.as_slice())),
+            http_only: json.find(&"httpOnly".to_string()).and_then(|flag| flag.as_boolean()).unwrap_or(false),
+            secure: json.find(&"secure".to_string()).and_then(|flag| flag.as_boolean()).unwrap_or(false)
+        })
+    }
+
+    /// Write a HAR cookie object.
+    pub fn to_json(&self) -> Json {
+        let mut object = TreeMap::new();
+        object.insert("name".to_string(), json::String(self.name.clone()));
+        object.insert("value".to_string(), json::String(self.value.clone()));
+        match self.path {
+            Some(ref path) => { object.insert("path".to_string(), json::String(path.clone())); },
+            None           => ()
+        }
+        match self.domain {
+            Some(ref domain) => { object.insert("domain".to_string(), json::String(domain.clone())); },
+            None             => ()
+        }
+        match self.expires {
+            Some(expires) => { object.insert("expires".to_string(), json::String(format_iso8601(expires))); },
+            None          => ()
+        }
+        object.insert("httpOnly".to_string(), Boolean(self.http_only));
+        object.insert("secure".to_string(), Boolean(self.secure));
+        Object(object)
+    }
+
+    /// Describe a stored cookie, as a browser records it.
+    ///
+    /// Host-only cookies are recorded without a `domain`.
+    pub fn from_stored(cookie: &StoredCookie) -> HarCookie {
+        HarCookie {
+            name: cookie.name.clone(),
+            value: cookie.value.clone(),
+            path: Some(cookie.path.clone()),
+            domain: if cookie.host_only { None } else { Some(cookie.domain.clone()) },
+            expires: cookie.expires,
+            http_only: cookie.http_only,
+            secure: cookie.secure
+        }
+    }
+
+    /// Write the Set-Cookie header which would have set this cookie.
+    pub fn to_set_cookie(&self) -> String {
+        let mut header = self.name.clone().append("=").append(self.value.as_slice());
+        match self.expires {
+            Some(expires) => header.push_str(format!("; Expires={}", format_date(expires)).as_slice()),
+            None          => ()
+        }
+        match self.domain {
+            Some(ref domain) => header.push_str(format!("; Domain={}", domain).as_slice()),
+            None             => ()
+        }
+        match self.path {
+            Some(ref path) => header.push_str(format!("; Path={}", path).as_slice()),
+            None           => ()
+        }
+        if self.secure { header.push_str("; Secure") }
+        if self.http_only { header.push_str("; HttpOnly") }
+        header
+    }
+}
+
+fn field<'a>(json: &'a Json, name: &'static str) -> Result<&'a Json, HarError> {
+    json.find(&name.to_string()).ok_or(HarMissingField(name))
+}
+
+fn string_field(json: &Json, name: &'static str) -> Result<String, HarError> {
+    optional(json, name).ok_or(HarMissingField(name))
+}
+
+fn optional(json: &Json, name: &'static str) -> Option<String> {
+    json.find(&name.to_string()).and_then(|value| value.as_string()).map(|value| value.to_string())
+}
+
+fn cookies(message: &Json) -> Result<Vec<HarCookie>, HarError> {
+    match message.find(&"cookies".to_string()).and_then(|cookies| cookies.as_list()) {
+        Some(cookies) => cookies.iter().map(HarCookie::from_json).collect(),
+        None          => Ok(Vec::new())
+    }
+}
+
+// The values of every header `name`, matched case-insensitively
+fn headers(message: &Json, name: &str) -> Result<Vec<String>, HarError> {
+    let headers = match message.find(&"headers".to_string()).and_then(|headers| headers.as_list()) {
+        Some(headers) => headers,
+        None          => return Ok(Vec::new())
+    };
+    let mut values = Vec::new();
+    for header in headers.iter() {
+        let header_name = try!(string_field(header, "name"));
+        if header_name.as_slice().to_ascii_lower().as_slice() == name {
+            values.push(try!(string_field(header, "value")));
+        }
+    }
+    Ok(values)
+}
+
+// A cookie of a Cookie header, split as the parser splits it
+fn request_cookie((name, value): (&str, &str)) -> HarCookie {
+    HarCookie {
+        name: name.to_string(),
+        value: value.to_string(),
+        path: None,
+        domain: None,
+        expires: None,
+        http_only: false,
+        secure: false
+    }
+}
+
+// The cookie of a Set-Cookie header, with the attributes HAR records, as a `CookieStore` reads them
+fn response_cookie(header: &str, now: i64) -> Option<HarCookie> {
+    parse_set_cookie(header, now).map(|cookie| HarCookie {
+        name: cookie.name,
+        value: cookie.value,
+        path: cookie.path,
+        domain: cookie.domain,
+        expires: cookie.expires,
+        http_only: cookie.http_only,
+        secure: cookie.secure
+    })
+}
+
+// Parse an ISO 8601 date, as written by browsers: `2009-07-24T19:20:30.123+02:00`
+fn parse_iso8601(date: &str) -> Option<i64> {
+    if date.len() < 19 || !date.is_char_boundary(19) { return None }
+    let seconds = match time::strptime(date.slice_to(19), "%Y-%m-%dT%H:%M:%S") {
+        Ok(tm)  => tm.to_timespec().sec,
+        Err(_)  => return None
+    };
+    // Fractions of a second are ignored
+    let zone = date.slice_from(19).trim_left_chars(|c: char| c == '.' || c.is_digit());
+    let (sign, offset) = match zone.slice_shift_char() {
+        (None, _)           => return Some(seconds),
+        (Some('Z'), "")     => return Some(seconds),
+        (Some('+'), offset) => (1, offset),
+        (Some('-'), offset) => (-1, offset),
+        _                   => return None
+    };
+    let (hours, minutes) = match offset.find(':') {
+        Some(i) => (from_str::<i64>(offset.slice_to(i)), from_str::<i64>(offset.slice_from(i + 1))),
+        None    => return None
+    };
+    match (hours, minutes) {
+        (Some(hours), Some(minutes)) => Some(seconds - sign * (hours * 3600 + minutes * 60)),
+        _                            => None
+    }
+}
+
+fn format_iso8601(date: i64) -> String {
+    time::at_utc(time::Timespec::new(date, 0)).rfc3339()
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+    use serialize::json;
+    use super::*;
+    use super::{parse_iso8601, response_cookie};
+
+    static HAR: &'static str = r#"{"log": {"version": "1.2", "entries": [
+        {"request": {"method": "POST", "url": "https://www.example.com/login", "cookies": [], "headers": []},
+         "response": {"status": 302, "headers": [], "cookies": [
+            {"name": "sid", "value": "abc", "path": "/", "httpOnly": true, "secure": true},
+            {"name": "theme", "value": "dark", "domain": ".example.com", "expires": "2100-01-01T00:00:00.000Z"}
+         ]}},
+        {"request": {"method": "GET", "url": "https://www.example.com/account", "cookies": [],
+                     "headers": [{"name": "Cookie", "value": "sid=abc; theme=dark"}]},
+         "response": {"status": 200, "cookies": [],
+                      "headers": [{"name": "set-cookie", "value": "recent=1; Path=/account; HttpOnly"}]}}
+    ]}}"#;
+
+    #[test]
+    fn check_read_har() {
+        let entries = read_har(HAR).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].cookie_header(), None);
+        assert_eq!(entries[0].response_cookies[1], HarCookie {
+            name: "theme".to_string(),
+            value: "dark".to_string(),
+            path: None,
+            domain: Some(".example.com".to_string()),
+            expires: Some(4102444800),
+            http_only: false,
+            secure: false
+        });
+        // Cookies recorded only as headers are read from them
+        assert_eq!(entries[1].cookie_header(), Some("sid=abc; theme=dark".to_string()));
+        assert_eq!(entries[1].response_cookies[0].path, Some("/account".to_string()));
+        assert!(entries[1].response_cookies[0].http_only);
+
+        assert_eq!(read_har("{\"log\": {}}"), Err(HarMissingField("entries")));
+        assert_eq!(read_har("not json"), Err(HarSyntaxError));
+    }
+
+    #[test]
+    fn check_store_from_har() {
+        let mut store = store_from_har(read_har(HAR).unwrap().as_slice());
+        assert_eq!(store.cookie_header(&Url::parse("https://www.example.com/account").unwrap()),
+                   Some("recent=1; sid=abc; theme=dark".to_string()));
+
+        let cookies = har_cookies(&store);
+        let exported: Vec<HarCookie> = cookies.as_list().unwrap().iter().map(|cookie| HarCookie::from_json(cookie).unwrap()).collect();
+        assert_eq!(exported.len(), 3);
+        // Host-only cookies have no domain
+        assert_eq!(exported[0].domain, None);
+        assert_eq!(exported[1].domain, Some("example.com".to_string()));
+        assert_eq!(exported[1].expires, Some(4102444800));
+        assert!(exported[0].http_only && exported[0].secure);
+        // Exported cookies can be read again
+        assert_eq!(json::from_str(cookies.to_string().as_slice()).ok(), Some(cookies));
+    }
+
+    #[test]
+    fn check_response_cookie() {
+        // Set-Cookie headers are read as a store reads them
+        assert_eq!(response_cookie("a=\"b c\"; Domain=.Example.COM; Path=/a; Max-Age=60", 100), Some(HarCookie {
+            name: "a".to_string(),
+            value: "b c".to_string(),
+            path: Some("/a".to_string()),
+            domain: Some("example.com".to_string()),
+            expires: Some(160),
+            http_only: false,
+            secure: false
+        }));
+        assert_eq!(response_cookie("=b; Path=/", 100), None);
+    }
+
+    #[test]
+    fn check_iso8601() {
+        assert_eq!(parse_iso8601("2009-07-24T19:20:30.123+02:00"), Some(1248456030));
+        assert_eq!(parse_iso8601("2009-07-24T17:20:30Z"), Some(1248456030));
+        assert_eq!(parse_iso8601("2009-07-24T17:20:30"), Some(1248456030));
+        assert_eq!(parse_iso8601("2009-07-24"), None);
+        assert_eq!(parse_iso8601(super::format_iso8601(1248456030).as_slice()), Some(1248456030));
+    }
+}
@@ -0,0 +1,240 @@
+//! itsdangerous compatibility - share signed cookies with Flask and other Python services
+
+use serialize::base64::{ToBase64, FromBase64, URL_SAFE, Config};
+use crypto::util::fixed_time_eq;
+use time;
+use super::{ValueCodec, Percent, HmacDigest, HmacSha1};
+use super::{Verification, InvalidSignature, Malformed, Expired};
+use super::codec::{to_json_message, from_json_message};
+
+/// A `ValueCodec` for values signed by itsdangerous' `TimestampSigner`.
+///
+/// Values are signed as `value.timestamp.signature`, in URL-safe base64,
+/// with the salt and key derivation conventions of itsdangerous.
+/// Values are percent-encoded, which leaves serialized values unchanged.
+#[deriving(Clone)]
+pub struct ItsdangerousCodec {
+    secret_key: Vec<u8>,
+    salt: String,
+    key_derivation: KeyDerivation,
+    digest: HmacDigest,
+    max_age: Option<i64>,
+    serialized: bool
+}
+
+/// How itsdangerous derives a signing key from the secret key and salt.
+#[deriving(Clone, PartialEq, Show)]
+pub enum KeyDerivation {
+    /// `digest(salt + "signer" + secret_key)`, the default.
+    DjangoConcat,
+    /// `digest(salt + secret_key)`.
+    Concat,
+    /// `hmac(secret_key, salt)`, as used by Flask sessions.
+    HmacDerivation,
+    /// The secret key, unchanged.
+    NoDerivation
+}
+
+impl ItsdangerousCodec {
+    /// Read and write values signed by a `TimestampSigner`, with its defaults:
+    /// the salt `itsdangerous.Signer`, `DjangoConcat` key derivation and HMAC SHA1.
+    pub fn new(secret_key: &str) -> ItsdangerousCodec {
+        ItsdangerousCodec {
+            secret_key: secret_key.as_bytes().to_vec(),
+            salt: "itsdangerous.Signer".to_string(),
+            key_derivation: DjangoConcat,
+            digest: HmacSha1,
+            max_age: None,
+            serialized: false
+        }
+    }
+
+    /// Read and write Flask session cookies, signed with the application's `SECRET_KEY`.
+    ///
+    /// Sessions are JSON, serialized by a `URLSafeTimedSerializer`. Compressed sessions
+    /// are not supported, and will not be parsed.
+    pub fn flask_session(secret_key: &str) -> ItsdangerousCodec {
+        ItsdangerousCodec::new(secret_key)
+            .salt("cookie-session")
+            .key_derivation(HmacDerivation)
+            .serialized()
+    }
+
+    /// Set the salt.
+    pub fn salt(mut self, salt: &str) -> ItsdangerousCodec {
+        self.salt = salt.to_string();
+        self
+    }
+
+    /// Set how the signing key is derived.
+    pub fn key_derivation(mut self, key_derivation: KeyDerivation) -> ItsdangerousCodec {
+        self.key_derivation = key_derivation;
+        self
+    }
+
+    /// Set the digest used to derive keys and sign.
+    pub fn digest(mut self, digest: HmacDigest) -> ItsdangerousCodec {
+        self.digest = digest;
+        self
+    }
+
+    /// Reject values signed more than `seconds` ago, or in the future,
+    /// as with the `max_age` of `TimestampSigner.unsign`.
+    pub fn max_age(mut self, seconds: i64) -> ItsdangerousCodec {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Read and write values serialized as JSON by a `URLSafeTimedSerializer`.
+    pub fn serialized(mut self) -> ItsdangerousCodec {
+        self.serialized = true;
+        self
+    }
+
+    // `Signer.derive_key`
+    fn derive_key(&self) -> Vec<u8> {
+        let salt = self.salt.as_bytes();
+        match self.key_derivation {
+            DjangoConcat   => self.digest.hash(salt.to_vec().append(b"signer").append(self.secret_key.as_slice()).as_slice()),
+            Concat         => self.digest.hash(salt.to_vec().append(self.secret_key.as_slice()).as_slice()),
+            HmacDerivation => self.digest.hmac(self.secret_key.as_slice(), salt),
+            NoDerivation   => self.secret_key.clone()
+        }
+    }
+
+    // `Signer.get_signature`
+    fn signature(&self, value: &str) -> String {
+        self.digest.hmac(self.derive_key().as_slice(), value.as_bytes()).as_slice().to_base64(base64_config())
+    }
+}
+
+impl ValueCodec for ItsdangerousCodec {
+    fn encode(&self, _name: &str, value: &str) -> String {
+        let value = if self.serialized {
+            to_json_message(value).as_bytes().to_base64(base64_config())
+        } else {
+            value.to_string()
+        };
+        let timestamp = int_to_bytes(time::get_time().sec).as_slice().to_base64(base64_config());
+        let value = value.append(".").append(timestamp.as_slice());
+        let signature = self.signature(value.as_slice());
+        Percent.encode(value.append(".").append(signature.as_slice()).as_slice())
+    }
+
+    fn decode(&self, name: &str, value: &str) -> Option<String> {
+        self.verify(name, value).ok()
+    }
+
+    fn verify(&self, _name: &str, value: &str) -> Result<String, Verification> {
+        let value = try!(Percent.decode(value)
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(Malformed));
+        let (value, signature) = try!(rsplit(value.as_slice()).ok_or(Malformed));
+        let expected = self.signature(value);
+        if !fixed_time_eq(signature.as_bytes(), expected.as_bytes()) { return Err(InvalidSignature) }
+
+        let (value, timestamp) = try!(rsplit(value).ok_or(Malformed));
+        let timestamp = match timestamp.from_base64() {
+            Ok(ref bytes) if bytes.len() <= 8 => bytes_to_int(bytes.as_slice()),
+            _                                 => return Err(Malformed)
+        };
+        match self.max_age {
+            Some(max_age) => {
+                let age = time::get_time().sec - timestamp;
+                if age > max_age || age < 0 { return Err(Expired) }
+            },
+            None          => ()
+        }
+
+        if self.serialized {
+            // Compressed payloads are prefixed with `.`
+            if value.starts_with(".") { return Err(Malformed) }
+            value.from_base64().ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .and_then(from_json_message)
+                .ok_or(Malformed)
+        } else {
+            Ok(value.to_string())
+        }
+    }
+}
+
+fn base64_config() -> Config {
+    Config { pad: false, ..URL_SAFE }
+}
+
+fn rsplit<'a>(value: &'a str) -> Option<(&'a str, &'a str)> {
+    value.rfind('.').map(|i| (value.slice_to(i), value.slice_from(i + 1)))
+}
+
+// Big-endian, without leading zeroes, as `itsdangerous.encoding.int_to_bytes`
+fn int_to_bytes(value: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut value = value as u64;
+    while value > 0 {
+        bytes.push((value & 0xff) as u8);
+        value = value >> 8;
+    }
+    bytes.reverse();
+    bytes
+}
+
+fn bytes_to_int(bytes: &[u8]) -> i64 {
+    bytes.iter().fold(0u64, |value, &byte| (value << 8) | byte as u64) as i64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::codec::ValueCodec;
+    use super::super::parser::{Expired, Malformed};
+
+    // Test vectors produced by itsdangerous 2.2 and Flask 3.1, with this secret key,
+    // signed at timestamp 1400000000
+    static SECRET_KEY: &'static str = "iron secret";
+
+    #[test]
+    fn check_signed() {
+        // TimestampSigner("iron secret").sign("hello")
+        let codec = ItsdangerousCodec::new(SECRET_KEY);
+        let value = "hello.U3JOAA.y_7O2TtVUHT67NpAELaPCsvNdCs";
+        assert_eq!(codec.decode("thing", value), Some("hello".to_string()));
+        assert_eq!(codec.decode("thing", "hello.U3JOAA.y_7O2TtVUHT67NpAELaPCsvNdCt"), None);
+        // Signed too long ago
+        assert_eq!(codec.clone().max_age(60).decode("thing", value), None);
+        assert_eq!(codec.clone().max_age(60).verify("thing", value), Err(Expired));
+        assert_eq!(codec.verify("thing", "hello"), Err(Malformed));
+    }
+
+    #[test]
+    fn check_key_derivation() {
+        // TimestampSigner("iron secret", salt="preferences", key_derivation="hmac").sign("dark")
+        let value = "dark.U3JOAA._AB660mdUQS5TceGjFQc40vnaYQ";
+        let codec = ItsdangerousCodec::new(SECRET_KEY).salt("preferences");
+        assert_eq!(codec.clone().key_derivation(HmacDerivation).decode("thing", value), Some("dark".to_string()));
+        assert_eq!(codec.decode("thing", value), None);
+    }
+
+    #[test]
+    fn check_flask_session() {
+        let codec = ItsdangerousCodec::flask_session(SECRET_KEY);
+        // session["user_id"] = 42
+        assert_eq!(codec.decode("session", "eyJ1c2VyX2lkIjo0Mn0.U3JOAA.Q6o9sU7jTbzjJVpbwfJvZHNx3_E"),
+                   Some("j:{\"user_id\":42}".to_string()));
+        assert_eq!(codec.decode("session", "ImhlbGxvIg.U3JOAA.qrvkU33SjxBHgxb950XSPZRoz9w"),
+                   Some("hello".to_string()));
+        // The payload is serialized exactly as Flask serializes it
+        assert!(codec.encode("session", "j:{\"user_id\":42}").as_slice().starts_with("eyJ1c2VyX2lkIjo0Mn0."));
+    }
+
+    #[test]
+    fn check_roundtrip() {
+        for codec in [ItsdangerousCodec::new(SECRET_KEY), ItsdangerousCodec::flask_session(SECRET_KEY)].iter() {
+            let codec = codec.clone().max_age(60);
+            for &value in ["hello", "a b.c", "✓", "j:{\"id\":[1,2]}"].iter() {
+                let encoded = codec.encode("thing", value);
+                assert_eq!(codec.decode("thing", encoded.as_slice()), Some(value.to_string()));
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Key derivation - independent keys from one master secret
+
+use std::sync::Arc;
+use super::{HmacDigest, HmacSha256, SecretError, Signer, PrecomputedMac};
+use secret::check_secret;
+
+/// A master secret, from which an independent key is derived
+/// for each purpose and each cookie name.
+///
+/// Keys are derived with `info` (or, for PBKDF2, a salt) of
+/// `iron-cookie:<purpose>:<name>`, or `iron-cookie:other:<tag>:<name>` for an `OtherKey`,
+/// so that no derived key reveals the master secret or any other key.
+#[deriving(Clone)]
+pub struct MasterKey {
+    secret: Vec<u8>,
+    kdf: Kdf
+}
+
+/// The function used to derive keys from a `MasterKey`.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Kdf {
+    /// HKDF, as specified by RFC 5869, with an empty salt.
+    ///
+    /// This suits random master secrets, and is the default, with `HmacSha256`.
+    Hkdf(HmacDigest),
+    /// PBKDF2, with the given number of iterations.
+    ///
+    /// This suits master secrets which are passphrases, but is run
+    /// for every cookie signed or verified, so is far slower.
+    Pbkdf2(HmacDigest, u32)
+}
+
+/// What a derived key is used for.
+#[deriving(Clone, PartialEq, Show)]
+pub enum KeyPurpose {
+    /// Signing cookies, as by the `CookieParser`.
+    SigningKey,
+    /// Encrypting cookies.
+    EncryptionKey,
+    /// CSRF tokens.
+    CsrfKey,
+    /// Session identifiers or session storage.
+    SessionKey,
+    /// Any other purpose, named by a tag which must not be empty, nor contain `:`.
+    ///
+    /// Tags have their own namespace, so `OtherKey("signing")` is not `SigningKey`.
+    OtherKey(&'static str)
+}
+
+impl KeyPurpose {
+    // The purpose, as written in the `info` of derived keys, or `None` if its tag is invalid
+    fn info(&self) -> Option<String> {
+        Some(match *self {
+            SigningKey    => "signing".to_string(),
+            EncryptionKey => "encryption".to_string(),
+            CsrfKey       => "csrf".to_string(),
+            SessionKey    => "session".to_string(),
+            OtherKey(tag) if tag.is_empty() || tag.contains_char(':') => return None,
+            OtherKey(tag) => format!("other:{}", tag)
+        })
+    }
+}
+
+impl MasterKey {
+    /// Derive keys from `secret` with HKDF SHA-256.
+    ///
+    /// The secret must pass `check_secret`.
+    pub fn new(secret: &str) -> Result<MasterKey, SecretError> {
+        try!(check_secret(secret));
+        Ok(MasterKey {
+            secret: secret.as_bytes().to_vec(),
+            kdf: Hkdf(HmacSha256)
+        })
+    }
+
+    /// Set the function used to derive keys.
+    pub fn kdf(mut self, kdf: Kdf) -> MasterKey {
+        self.kdf = kdf;
+        self
+    }
+
+    /// Derive a key of `len` bytes for `purpose` and the cookie `name`.
+    ///
+    /// Returns `None` for an `OtherKey` whose tag is empty or contains `:`.
+    pub fn derive(&self, purpose: KeyPurpose, name: &str, len: uint) -> Option<Vec<u8>> {
+        let info = match purpose.info() {
+            Some(purpose) => format!("iron-cookie:{}:{}", purpose, name),
+            None          => return None
+        };
+        Some(match self.kdf {
+            Hkdf(ref digest)               => digest.hkdf(self.secret.as_slice(), [], info.as_bytes(), len),
+            Pbkdf2(ref digest, iterations) => digest.pbkdf2(self.secret.as_slice(), info.as_bytes(), iterations, len)
+        })
+    }
+}
+
+/// The secret used to sign cookies, with the key state of each signer precomputed.
+///
+/// This is built once by the `CookieParser`, and shared by the `Cookie` of every request.
+pub struct SecretKeys {
+    secret: Option<Vec<u8>>,
+    master: Option<MasterKey>,
+    precomputed: Vec<(&'static str, Box<PrecomputedMac + Send + Sync>)>
+}
+
+impl SecretKeys {
+    /// Sign with `secret` itself, or not at all.
+    pub fn new(secret: Option<&str>) -> SecretKeys {
+        SecretKeys {
+            secret: secret.map(|secret| secret.as_bytes().to_vec()),
+            master: None,
+            precomputed: Vec::new()
+        }
+    }
+
+    /// Sign with the keys derived from `master` for each cookie name.
+    pub fn derived(master: MasterKey) -> SecretKeys {
+        SecretKeys {
+            secret: None,
+            master: Some(master),
+            precomputed: Vec::new()
+        }
+    }
+
+    /// Copy these keys, precomputing the state of the secret for each of `signers`.
+    ///
+    /// Keys derived from a `MasterKey` differ for each name, so are not precomputed.
+    pub fn precompute(&self, signers: &[&Arc<Box<Signer + Send + Sync>>]) -> SecretKeys {
+        let precomputed = match self.secret {
+            Some(ref secret) => signers
+                .iter()
+                .filter_map(|signer| signer.precompute(secret.as_slice()).map(|mac| (signer.tag(), mac)))
+                .collect(),
+            None             => Vec::new()
+        };
+        SecretKeys {
+            secret: self.secret.clone(),
+            master: self.master.clone(),
+            precomputed: precomputed
+        }
+    }
+
+    /// True if there is a secret to sign with.
+    pub fn is_signed(&self) -> bool {
+        self.secret.is_some() || self.master.is_some()
+    }
+
+    /// Authenticate `data` for the cookie `name` with `signer`.
+    ///
+    /// Returns `None` if there is no secret to sign with.
+    pub fn sign(&self, signer: &Arc<Box<Signer + Send + Sync>>, name: &str, data: &[u8]) -> Option<Vec<u8>> {
+        match (&self.master, &self.secret) {
+            (&Some(ref master), _)     => master.derive(SigningKey, name, 32).map(|key| signer.sign(key.as_slice(), data)),
+            (&None, &Some(ref secret)) => {
+                let tag = signer.tag();
+                Some(match self.precomputed.iter().find(|&&(precomputed, _)| precomputed == tag) {
+                    Some(&(_, ref mac)) => mac.sign(data),
+                    None                => signer.sign(secret.as_slice(), data)
+                })
+            },
+            (&None, &None)             => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serialize::hex::ToHex;
+    use super::super::mac::HmacSha256;
+    use super::super::secret::SecretTooShort;
+
+    static SECRET: &'static str = "f3a1c94e0b7d2e68a5c0d9b4e1f7a23c";
+
+    #[test]
+    fn check_derive() {
+        let master = MasterKey::new(SECRET).unwrap();
+        assert_eq!(master.derive(SigningKey, "thing", 32).unwrap().as_slice().to_hex(),
+            "c46943b1bcf81e9bab7b26ef9ffeb8e539c5e63531f396496c3e4ff4da4bc863".to_string());
+        assert_eq!(master.clone().kdf(Pbkdf2(HmacSha256, 1000)).derive(SigningKey, "thing", 32).unwrap().as_slice().to_hex(),
+            "4004f96fd9a097a2ded5d5902f8d134751e1de101428a4fe3f76fe91e3a45851".to_string());
+    }
+
+    #[test]
+    fn check_weak_secret() {
+        assert_eq!(MasterKey::new("@zzmp").err(), Some(SecretTooShort));
+    }
+
+    #[test]
+    fn check_independent() {
+        let master = MasterKey::new(SECRET).unwrap();
+        let signing = master.derive(SigningKey, "thing", 32);
+        assert!(signing.is_some());
+        assert!(signing != master.derive(SigningKey, "other", 32));
+        assert!(signing != master.derive(EncryptionKey, "thing", 32));
+        assert!(signing != master.derive(OtherKey("signing2"), "thing", 32));
+        assert!(master.derive(CsrfKey, "", 32) != master.derive(SessionKey, "", 32));
+        // Other purposes never derive a built-in purpose's key
+        assert!(signing != master.derive(OtherKey("signing"), "thing", 32));
+        assert!(master.derive(OtherKey("signing"), "thing", 32).is_some());
+    }
+
+    #[test]
+    fn check_invalid_tag() {
+        let master = MasterKey::new(SECRET).unwrap();
+        assert_eq!(master.derive(OtherKey(""), "thing", 32), None);
+        assert_eq!(master.derive(OtherKey("signing:thing"), "", 32), None);
+    }
+}
@@ -0,0 +1,203 @@
+//! Lazy parsing - parse cookies only when a handler asks for them
+
+use std::mem;
+use super::{Cookie, CookieParser, Rejection, Verification};
+use parser::{new_cookie, parse_one};
+
+/// A lazily parsed cookie.
+///
+/// This is the type stored in the alloy by a `lazy` `CookieParser`.
+/// Nothing is parsed until a handler first asks for a cookie: `get` parses
+/// and verifies only the cookie asked for, and `cookie` parses them all.
+/// It shares the parser's configuration, so costs little for requests which never ask.
+pub struct LazyCookie {
+    parser: CookieParser,
+    header: Option<String>,
+    signer: Option<Cookie>,
+    parsed: Option<Cookie>,
+    // The values found by `get`, by name; handlers ask for few, so they are searched in order
+    values: Vec<(String, Option<String>)>,
+    // The rejections and outcomes of the cookies found by `get`, for observers
+    rejected: Vec<(String, Rejection)>,
+    outcomes: Vec<(String, Verification)>
+}
+
+impl LazyCookie {
+    /// Create a lazy cookie for the Cookie `header` of a request, if it had one.
+    pub fn new(parser: CookieParser, header: Option<String>) -> LazyCookie {
+        LazyCookie {
+            parser: parser,
+            header: header,
+            signer: None,
+            parsed: None,
+            values: Vec::new(),
+            rejected: Vec::new(),
+            outcomes: Vec::new()
+        }
+    }
+
+    /// Find the value of the cookie `name`, parsing and verifying only that cookie.
+    ///
+    /// This is the value that `Cookie.get` would find.
+    pub fn get(&mut self, name: &str) -> Option<&String> {
+        if self.parsed.is_some() {
+            return self.parsed.as_ref().unwrap().get(name)
+        }
+        if !self.values.iter().any(|&(ref key, _)| key.as_slice() == name) {
+            if self.signer.is_none() {
+                self.signer = Some(new_cookie(&self.parser));
+            }
+            let value = match self.header {
+                Some(ref header) => parse_one(&self.parser, self.signer.as_ref().unwrap(), header.as_slice(), name,
+                                              &mut self.rejected, &mut self.outcomes),
+                None             => None
+            };
+            self.values.push((name.to_string(), value));
+        }
+        self.values.iter()
+            .find(|&&(ref key, _)| key.as_slice() == name)
+            .and_then(|&(_, ref value)| value.as_ref())
+    }
+
+    /// Parse every cookie, the first time it is called.
+    ///
+    /// This is the `Cookie` that a parser which is not `lazy` would store.
+    pub fn cookie(&mut self) -> &Cookie {
+        if self.parsed.is_none() {
+            let cookie = match self.header {
+                Some(ref header) => self.parser.parse(header.as_slice()),
+                None             => new_cookie(&self.parser)
+            };
+            self.parsed = Some(cookie);
+        }
+        self.parsed.as_ref().unwrap()
+    }
+
+    /// True if every cookie has been parsed, by `cookie`.
+    pub fn is_parsed(&self) -> bool {
+        self.parsed.is_some()
+    }
+
+    /// A `Cookie` to set cookies with, through `SetCookie`, without parsing any.
+    pub fn signer(&mut self) -> &Cookie {
+        if self.signer.is_none() {
+            self.signer = Some(new_cookie(&self.parser));
+        }
+        self.signer.as_ref().unwrap()
+    }
+}
+
+// Take the rejections and outcomes of every cookie parsed so far, to notify observers.
+// Once every cookie is parsed, these are the parsed `Cookie`'s, so none is reported twice.
+pub fn take_checked(lazy: &mut LazyCookie) -> Cookie {
+    let mut checked = new_cookie(&lazy.parser);
+    match lazy.parsed {
+        Some(ref cookie) => {
+            checked.rejected = cookie.rejected.clone();
+            checked.outcomes = cookie.outcomes.clone();
+        },
+        None             => {
+            checked.rejected = mem::replace(&mut lazy.rejected, Vec::new());
+            checked.outcomes = mem::replace(&mut lazy.outcomes, Vec::new());
+        }
+    }
+    checked
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use iron::{Request, Middleware};
+    use test::mock::{request, response};
+    use observer::{CookieMetrics, CookieCounts};
+    use super::*;
+    use super::super::parser::*;
+
+    static HEADER: &'static str =
+        "session=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; \
+         tampered=s:thing.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; \
+         plain=thung; a%20b=c%20d; dup=first; dup=second; prefs=j%3A%7B%22theme%22%3A%22dark%22%7D";
+
+    #[test]
+    fn check_get() {
+        let parser = CookieParser::signed_unchecked("@zzmp".to_string());
+        let mut lazy = LazyCookie::new(parser, Some(HEADER.to_string()));
+        assert_eq!(lazy.get("session"), Some(&"thung".to_string()));
+        assert_eq!(lazy.get("tampered"), None);
+        assert_eq!(lazy.get("plain"), None);
+        assert!(!lazy.is_parsed());
+        assert_eq!(lazy.cookie().get("session"), Some(&"thung".to_string()));
+        assert!(lazy.is_parsed());
+    }
+
+    #[test]
+    fn check_same_as_parse() {
+        // Every name should be found as a parser which is not lazy would find it
+        let names = ["session", "tampered", "plain", "a b", "a%20b", "dup", "prefs", "a", "missing"];
+        // A JSON duplicate is kept apart, whichever comes first
+        for &header in [HEADER, "a=1; a=j:{}", "a=j:{}; a=1"].iter() {
+            for duplicates in [FirstWins, LastWins, RejectDuplicates].iter() {
+                let parser = CookieParser::new().duplicates(duplicates.clone());
+                let cookie = parser.parse(header);
+                let mut lazy = LazyCookie::new(parser, Some(header.to_string()));
+                for &name in names.iter() {
+                    assert_eq!(lazy.get(name).map(|val| val.clone()), cookie.get(name).map(|val| val.clone()));
+                }
+            }
+        }
+        let mut lazy = LazyCookie::new(CookieParser::new().duplicates(LastWins), Some("a=1; a=j:{}".to_string()));
+        assert_eq!(lazy.get("a"), Some(&"1".to_string()));
+    }
+
+    #[test]
+    fn check_lazy_parser() {
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        req.headers.extensions.insert("Cookie".to_string(), HEADER.to_string());
+        CookieParser::new().lazy().enter(&mut req, &mut response::new());
+        let lazy = req.alloy.find_mut::<LazyCookie>().unwrap();
+        // Nothing is built until a cookie is asked for
+        assert!(lazy.signer.is_none() && lazy.values.is_empty());
+        assert_eq!(lazy.get("plain"), Some(&"thung".to_string()));
+        assert!(lazy.signer().map.is_empty());
+    }
+
+    fn lazy_request(parser: &mut CookieParser) -> Request {
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        req.headers.extensions.insert("Cookie".to_string(), HEADER.to_string());
+        parser.enter(&mut req, &mut response::new());
+        req
+    }
+
+    #[test]
+    fn check_lazy_observers() {
+        // Only the cookies parsed are reported, on exit
+        let metrics = Arc::new(CookieMetrics::new());
+        let mut parser = CookieParser::signed_unchecked("@zzmp".to_string()).lazy().observer(metrics.clone());
+        let mut req = lazy_request(&mut parser);
+        assert_eq!(metrics.counts().accepted, 0);
+        req.alloy.find_mut::<LazyCookie>().unwrap().get("session");
+        req.alloy.find_mut::<LazyCookie>().unwrap().get("tampered");
+        parser.exit(&mut req, &mut response::new());
+        assert_eq!(metrics.counts(), CookieCounts {
+            accepted: 1,
+            rejected: 0,
+            oversized: 0,
+            invalid_signature: 1,
+            unsigned: 0,
+            malformed: 0,
+            expired: 0
+        });
+
+        // Once every cookie is parsed, each is reported once, as by a parser which is not lazy
+        let lazy_metrics = Arc::new(CookieMetrics::new());
+        let mut parser = CookieParser::signed_unchecked("@zzmp".to_string()).lazy().observer(lazy_metrics.clone());
+        let mut req = lazy_request(&mut parser);
+        req.alloy.find_mut::<LazyCookie>().unwrap().get("session");
+        req.alloy.find_mut::<LazyCookie>().unwrap().cookie();
+        parser.exit(&mut req, &mut response::new());
+
+        let metrics = Arc::new(CookieMetrics::new());
+        lazy_request(&mut CookieParser::signed_unchecked("@zzmp".to_string()).observer(metrics.clone()));
+        assert_eq!(lazy_metrics.counts(), metrics.counts());
+    }
+}
@@ -2,15 +2,13 @@
 #![doc(html_logo_url = "https://avatars0.githubusercontent.com/u/7853871?s=128", html_favicon_url = "https://avatars0.githubusercontent.com/u/7853871?s=256", html_root_url = "http://ironframework.io/core/cookie")]
 #![crate_name = "cookie"]
 #![deny(missing_doc)]
-#![feature(phase)]
 #![feature(globs)]
 
 //! Cookie parsing/setting middleware for the [iron](https://github.com/iron/iron) framework.
 
+extern crate libc;
 extern crate time;
 extern crate rustc;
-extern crate regex;
-#[phase(plugin)] extern crate regex_macros;
 extern crate url;
 extern crate serialize;
 extern crate iron;
@@ -18,12 +16,63 @@ extern crate http;
 extern crate "rust-crypto" as crypto;
 #[cfg(test)]
 extern crate "iron-test" as test;
+#[cfg(test)]
+extern crate quickcheck;
+#[cfg(feature = "testing")]
+extern crate "iron-test" as iron_test;
 
 pub use cookie::Cookie;
+pub use cookie::{SignatureFormat, HexSignature, ExpressSignature};
 pub use parser::CookieParser;
+pub use lazy::LazyCookie;
+pub use parser::{DuplicatePolicy, FirstWins, LastWins, RejectDuplicates};
+pub use parser::{Validation, Lenient, Strict};
+pub use parser::{Rejection, InvalidName, InvalidValue, InvalidEncoding, Oversized};
+pub use parser::{Verification, Verified, InvalidSignature, Unsigned, Malformed, Expired, Unverifiable};
 pub use response::SetCookie;
 pub use response::HeaderCollection;
+pub use codec::{Encoding, Percent, Base64Url, Raw};
+pub use codec::ValueCodec;
+pub use mac::{HmacDigest, HmacSha1, HmacSha256, HmacSha384, HmacSha512};
+pub use mac::{Signer, Blake2bMac, PrecomputedMac};
+pub use keys::{MasterKey, Kdf, Hkdf, Pbkdf2};
+pub use keys::{KeyPurpose, SigningKey, EncryptionKey, CsrfKey, SessionKey, OtherKey};
+pub use secret::{check_secret, generate_secret, write_secret, read_secret, secret_file, MIN_SECRET_LEN};
+pub use secret::{SecretError, SecretTooShort, LowEntropySecret, PlaceholderSecret};
+pub use observer::{CookieObserver, CookieMetrics, CookieCounts};
+pub use rails::RailsCodec;
+// The Rails digests, as first published, are now any `HmacDigest`
+pub use mac::HmacDigest as RailsDigest;
+pub use mac::HmacSha1 as RailsSha1;
+pub use mac::HmacSha256 as RailsSha256;
+pub use django::DjangoCodec;
+pub use itsdangerous::{ItsdangerousCodec, KeyDerivation, DjangoConcat, Concat, HmacDerivation, NoDerivation};
+pub use response::{SetCookieError, InsecurePrefix, HostPrefixDomain, HostPrefixPath};
+pub use lint::{lint, lint_dump, MAX_COOKIE_SIZE};
+pub use lint::{LintWarning, MalformedCookie, SessionNotSecure, SessionNotHttpOnly, SameSiteNoneNotSecure};
+pub use lint::{BadPrefix, TooLarge, BadDomain, BadAttributeValue, NonStandardAttribute};
+pub use store::{CookieStore, StoredCookie};
+pub use har::{HarCookie, HarEntry, read_har, store_from_har, har_cookies};
+pub use har::{HarError, HarSyntaxError, HarMissingField};
+pub use consent::{ConsentPolicy, Consent, ConsentChoice, QueuedCookie};
+pub use consent::{ConsentCategory, Essential, Analytics, Marketing};
+pub use consent::{ConsentOutcome, Allowed, Blocked, Queued, Uncategorised};
 
 mod parser;
+mod lazy;
 mod response;
 mod cookie;
+mod codec;
+mod mac;
+mod keys;
+mod secret;
+mod observer;
+mod rails;
+mod django;
+mod itsdangerous;
+mod lint;
+mod store;
+mod har;
+mod consent;
+#[cfg(feature = "testing")]
+pub mod testing;
@@ -0,0 +1,282 @@
+//! Linting - check Set-Cookie headers for common mistakes
+
+use std::ascii::AsciiExt;
+use super::{HeaderCollection, SetCookieError};
+use parser::split_set_cookie;
+
+/// The size of a cookie, in bytes, which browsers are required to store.
+///
+/// See [RFC 6265, section 6.1](http://tools.ietf.org/html/rfc6265#section-6.1).
+pub static MAX_COOKIE_SIZE: uint = 4096;
+
+// Attributes defined by RFC 6265 and its successors, with those widely supported
+static ATTRIBUTES: &'static [&'static str] = &[
+    "expires", "max-age", "domain", "path", "secure", "httponly", "samesite", "priority", "partitioned"
+];
+
+// Words of names which suggest a cookie authenticates its user, including
+// the whole names used by common frameworks, such as PHP's `PHPSESSID`
+static SESSION_NAMES: &'static [&'static str] = &[
+    "session", "sess", "sid", "sessid", "sessionid", "auth", "token", "jwt", "csrf", "xsrf",
+    "remember", "login", "phpsessid", "jsessionid", "aspsessionid", "csrftoken", "authtoken"
+];
+
+/// A problem found in a Set-Cookie header by `lint`.
+#[deriving(Clone, PartialEq, Show)]
+pub enum LintWarning {
+    /// The header has no cookie-pair, or its name is empty.
+    MalformedCookie,
+    /// A cookie which looks like a session or credential is not `Secure`.
+    SessionNotSecure,
+    /// A cookie which looks like a session or credential is not `HttpOnly`.
+    SessionNotHttpOnly,
+    /// `SameSite=None` is only accepted by browsers on `Secure` cookies.
+    SameSiteNoneNotSecure,
+    /// The cookie name's `__Secure-` or `__Host-` prefix is not satisfied.
+    BadPrefix(SetCookieError),
+    /// The cookie is larger than browsers must store, in bytes.
+    TooLarge(uint),
+    /// The `Domain` attribute is not a valid domain name.
+    BadDomain(String),
+    /// An attribute has a value browsers will ignore, by attribute name.
+    BadAttributeValue(String),
+    /// An attribute is not defined by any standard, such as `Http-Only`.
+    NonStandardAttribute(String)
+}
+
+impl LintWarning {
+    /// Describe the problem, for people reading lint results.
+    pub fn message(&self) -> String {
+        match *self {
+            MalformedCookie                  => "no cookie name and value".to_string(),
+            SessionNotSecure                 => "session cookie is not Secure".to_string(),
+            SessionNotHttpOnly               => "session cookie is not HttpOnly".to_string(),
+            SameSiteNoneNotSecure            => "SameSite=None requires Secure".to_string(),
+            BadPrefix(ref error)             => format!("name prefix is not satisfied: {}", error),
+            TooLarge(size)                   => format!("{} bytes is over the limit of {}", size, MAX_COOKIE_SIZE),
+            BadDomain(ref domain)            => format!("invalid Domain `{}`", domain),
+            BadAttributeValue(ref attribute) => format!("invalid {} value", attribute),
+            NonStandardAttribute(ref name)   => format!("non-standard attribute `{}`", name)
+        }
+    }
+}
+
+/// Check a Set-Cookie header value for common mistakes.
+///
+/// Returns every problem found, in the order checked; none if the header looks right.
+pub fn lint(header: &str) -> Vec<LintWarning> {
+    let mut warnings = Vec::new();
+    let ((name, _), attributes) = match split_set_cookie(header) {
+        Some(split) => split,
+        None        => return vec![MalformedCookie]
+    };
+
+    let mut headers = HeaderCollection::empty();
+    let mut same_site_none = false;
+    for (key, value) in attributes.into_iter() {
+        let lowercase = key.to_ascii_lower();
+        match (lowercase.as_slice(), value) {
+            ("", _)                          => (),
+            ("secure", _)                    => headers.secure = true,
+            ("httponly", _)                  => headers.http_only = true,
+            ("path", Some(path))             => headers.path = Some(path.to_string()),
+            ("domain", Some(domain))         => {
+                headers.domain = Some(domain.to_string());
+                if !is_domain(domain) { warnings.push(BadDomain(domain.to_string())) }
+            },
+            ("max-age", Some(age)) if is_max_age(age) => (),
+            ("samesite", Some(same_site)) if is_same_site(same_site) => {
+                same_site_none = same_site.to_ascii_lower().as_slice() == "none";
+            },
+            ("expires", Some(_)) | ("priority", Some(_)) | ("partitioned", None) => (),
+            (attribute, _) if ATTRIBUTES.iter().any(|&known| known == attribute) => warnings.push(BadAttributeValue(key.to_string())),
+            _                                => warnings.push(NonStandardAttribute(key.to_string()))
+        }
+    }
+
+    if is_session(name) {
+        if !headers.secure { warnings.push(SessionNotSecure) }
+        if !headers.http_only { warnings.push(SessionNotHttpOnly) }
+    }
+    if same_site_none && !headers.secure { warnings.push(SameSiteNoneNotSecure) }
+    match headers.check_prefix(name) {
+        Err(error) => warnings.push(BadPrefix(error)),
+        Ok(())     => ()
+    }
+    if header.len() > MAX_COOKIE_SIZE { warnings.push(TooLarge(header.len())) }
+    warnings
+}
+
+/// Find and lint every Set-Cookie header in `text`, such as the output of `curl -i` or `curl -v`.
+///
+/// Lines without a `Set-Cookie:` header name are ignored. Text which is not an HTTP dump,
+/// having no status line and no header lines, is read as one header value per non-empty line.
+pub fn lint_dump(text: &str) -> Vec<(String, Vec<LintWarning>)> {
+    let headers: Vec<&str> = if text.lines_any().any(is_dump_line) {
+        text.lines_any().filter_map(set_cookie_value).collect()
+    } else {
+        text.lines_any().map(|line| line.trim()).filter(|line| !line.is_empty()).collect()
+    };
+    headers.into_iter().map(|header| (header.to_string(), lint(header))).collect()
+}
+
+// The value of a `Set-Cookie:` header line, after any `<` written by `curl -v`
+fn set_cookie_value<'a>(line: &'a str) -> Option<&'a str> {
+    let line = line.trim_left_chars(|c: char| c == '<' || c == ' ');
+    match line.find(':') {
+        Some(i) if line.slice_to(i).trim().to_ascii_lower().as_slice() == "set-cookie" => {
+            Some(line.slice_from(i + 1).trim())
+        },
+        _                                                                           => None
+    }
+}
+
+// A status or header line, after any `<` or `>` written by `curl -v`
+fn is_dump_line(line: &str) -> bool {
+    let line = line.trim_left_chars(|c: char| c == '<' || c == '>' || c == ' ');
+    if line.starts_with("HTTP/") { return true }
+    match line.find(':') {
+        Some(i) => i > 0 && line.slice_to(i).chars().all(is_label_char),
+        None    => false
+    }
+}
+
+// Names are split into words on `_`, `-` and `.`, so that `connect.sid` is matched but `possession` is not
+fn is_session(name: &str) -> bool {
+    let lowercase = name.to_ascii_lower();
+    lowercase.as_slice()
+        .split(|c: char| c == '_' || c == '-' || c == '.')
+        .any(|word| SESSION_NAMES.iter().any(|&known| known == word))
+}
+
+// A domain name of at least two labels, as browsers will not set cookies for a top-level domain
+fn is_domain(domain: &str) -> bool {
+    // A leading `.` is ignored, as described by RFC 6265, section 5.2.3
+    let domain = if domain.starts_with(".") { domain.slice_from(1) } else { domain };
+    let labels: Vec<&str> = domain.split('.').collect();
+    labels.len() >= 2 && labels.iter().all(|label| {
+        !label.is_empty() && label.len() <= 63 &&
+        !label.starts_with("-") && !label.ends_with("-") &&
+        label.chars().all(is_label_char)
+    })
+}
+
+// letter, digit or hyphen, as defined by RFC 1034, section 3.5
+fn is_label_char(c: char) -> bool {
+    match c {
+        'a'..'z' | 'A'..'Z' | '0'..'9' | '-' => true,
+        _                                   => false
+    }
+}
+
+fn is_max_age(age: &str) -> bool {
+    let digits = if age.starts_with("-") { age.slice_from(1) } else { age };
+    !digits.is_empty() && digits.chars().all(|c| c.is_digit())
+}
+
+fn is_same_site(same_site: &str) -> bool {
+    match same_site.to_ascii_lower().as_slice() {
+        "strict" | "lax" | "none" => true,
+        _                         => false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::TreeMap;
+    use test::mock::response;
+    use super::*;
+    use super::super::{Cookie, SetCookie, HeaderCollection, InsecurePrefix, HostPrefixPath};
+
+    #[test]
+    fn check_clean() {
+        assert_eq!(lint("session=abc; Path=/; Secure; HttpOnly; SameSite=Lax"), vec![]);
+        assert_eq!(lint("theme=dark; Max-Age=3600; Domain=.example.com"), vec![]);
+        assert_eq!(lint("__Host-sid=abc; Path=/; Secure; HttpOnly"), vec![]);
+    }
+
+    #[test]
+    fn check_session() {
+        assert_eq!(lint("sessionid=abc"), vec![SessionNotSecure, SessionNotHttpOnly]);
+        assert_eq!(lint("XSRF-TOKEN=abc; secure"), vec![SessionNotHttpOnly]);
+        assert_eq!(lint("connect.sid=abc; HttpOnly"), vec![SessionNotSecure]);
+        assert_eq!(lint("PHPSESSID=abc; Secure"), vec![SessionNotHttpOnly]);
+        // Only whole words are matched
+        assert_eq!(lint("assessment=abc"), vec![]);
+        assert_eq!(lint("possession=abc"), vec![]);
+        assert_eq!(lint("author=abc"), vec![]);
+    }
+
+    #[test]
+    fn check_same_site() {
+        assert_eq!(lint("theme=dark; SameSite=None"), vec![SameSiteNoneNotSecure]);
+        assert_eq!(lint("theme=dark; SameSite=None; Secure"), vec![]);
+        assert_eq!(lint("theme=dark; SameSite=Sometimes"), vec![BadAttributeValue("SameSite".to_string())]);
+    }
+
+    #[test]
+    fn check_prefixes() {
+        assert_eq!(lint("__Secure-theme=dark"), vec![BadPrefix(InsecurePrefix)]);
+        assert_eq!(lint("__Host-theme=dark; Secure; Path=/a"), vec![BadPrefix(HostPrefixPath)]);
+    }
+
+    #[test]
+    fn check_size() {
+        let header = "theme=".to_string().append("x".repeat(MAX_COOKIE_SIZE).as_slice());
+        assert_eq!(lint(header.as_slice()), vec![TooLarge(MAX_COOKIE_SIZE + 6)]);
+    }
+
+    #[test]
+    fn check_domain() {
+        for &domain in ["com", "example..com", "-example.com", "exam ple.com", "example.com.", ""].iter() {
+            let header = format!("theme=dark; Domain={}", domain);
+            assert_eq!(lint(header.as_slice()), vec![BadDomain(domain.to_string())]);
+        }
+    }
+
+    #[test]
+    fn check_attributes() {
+        assert_eq!(lint("theme=dark; Http-Only; Max-Age=soon; Comment=hi"),
+                   vec![NonStandardAttribute("Http-Only".to_string()),
+                        BadAttributeValue("Max-Age".to_string()),
+                        NonStandardAttribute("Comment".to_string())]);
+        assert_eq!(lint("=dark"), vec![MalformedCookie]);
+        assert_eq!(lint("dark"), vec![MalformedCookie]);
+    }
+
+    #[test]
+    fn check_set_cookie() {
+        // Cookies set by `SetCookie` are clean
+        let mut extensions = TreeMap::new();
+        extensions.insert("SameSite".to_string(), Some("Strict".to_string()));
+        let mut headers = HeaderCollection::host_prefixed();
+        headers.max_age = Some(3600);
+        headers.extensions = Some(extensions);
+        let mut res = response::new();
+        res.set_cookie(&Cookie::new(Some("@zzmp".to_string())), ("__Host-session".to_string(), "a b".to_string()), headers).unwrap();
+        let header = res.headers.extensions.find(&"Set-Cookie".to_string()).unwrap();
+        assert_eq!(lint(header.as_slice()), vec![]);
+    }
+
+    #[test]
+    fn check_dump() {
+        let dump = "HTTP/1.1 200 OK\r\nSet-Cookie: sid=abc; Secure; HttpOnly\r\n\
+                    set-cookie: theme=dark; SameSite=None\r\nContent-Length: 0\r\n";
+        assert_eq!(lint_dump(dump), vec![("sid=abc; Secure; HttpOnly".to_string(), vec![]),
+                                         ("theme=dark; SameSite=None".to_string(), vec![SameSiteNoneNotSecure])]);
+        let verbose = "> GET / HTTP/1.1\n< Set-Cookie: sid=abc\n";
+        assert_eq!(lint_dump(verbose), vec![("sid=abc".to_string(), vec![SessionNotSecure, SessionNotHttpOnly])]);
+        // Headers may also be given one per line
+        assert_eq!(lint_dump("a=b\n\nc=d; Bogus\n"), vec![("a=b".to_string(), vec![]),
+                                                         ("c=d; Bogus".to_string(), vec![NonStandardAttribute("Bogus".to_string())])]);
+        assert_eq!(lint_dump("theme=dark; Expires=Wed, 09 Jun 2100 10:18:14 GMT\n"),
+                   vec![("theme=dark; Expires=Wed, 09 Jun 2100 10:18:14 GMT".to_string(), vec![])]);
+    }
+
+    #[test]
+    fn check_dump_without_cookies() {
+        // Responses which set no cookies have nothing to lint
+        assert_eq!(lint_dump("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 0\r\n\r\n<html></html>\n"), vec![]);
+        assert_eq!(lint_dump("> GET / HTTP/1.1\n> Host: example.com\n< HTTP/1.1 204 No Content\n"), vec![]);
+    }
+}
@@ -0,0 +1,235 @@
+//! Message authentication - the digests used to sign cookies
+
+use std::cmp::min;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use crypto::sha2::{Sha256, Sha384, Sha512};
+use crypto::blake2b::Blake2b;
+use crypto::mac::Mac;
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+
+/// A digest for HMAC signatures and key derivation.
+#[deriving(Clone, PartialEq, Show)]
+pub enum HmacDigest {
+    /// SHA1, for compatibility with other frameworks only.
+    HmacSha1,
+    /// SHA-256.
+    HmacSha256,
+    /// SHA-384.
+    HmacSha384,
+    /// SHA-512.
+    HmacSha512
+}
+
+impl HmacDigest {
+    /// Authenticate `data` with `key`, returning the raw HMAC.
+    pub fn hmac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match *self {
+            HmacSha1   => mac(Hmac::new(Sha1::new(), key), data),
+            HmacSha256 => mac(Hmac::new(Sha256::new(), key), data),
+            HmacSha384 => mac(Hmac::new(Sha384::new(), key), data),
+            HmacSha512 => mac(Hmac::new(Sha512::new(), key), data)
+        }
+    }
+
+    /// Hash `data`, returning the raw digest.
+    pub fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            HmacSha1   => hash(Sha1::new(), data),
+            HmacSha256 => hash(Sha256::new(), data),
+            HmacSha384 => hash(Sha384::new(), data),
+            HmacSha512 => hash(Sha512::new(), data)
+        }
+    }
+
+    /// Derive a key of `len` bytes from `secret` with HKDF, as specified by RFC 5869.
+    pub fn hkdf(&self, secret: &[u8], salt: &[u8], info: &[u8], len: uint) -> Vec<u8> {
+        let prk = self.hmac(salt, secret);
+        assert!(len <= 255 * prk.len(), "HKDF keys are limited to 255 blocks");
+        let mut key = Vec::with_capacity(len);
+        let mut block = Vec::new();
+        let mut counter = 1u8;
+        while key.len() < len {
+            block = self.hmac(prk.as_slice(), block.append(info).append(&[counter]).as_slice());
+            let remaining = len - key.len();
+            key.push_all(block.slice_to(min(block.len(), remaining)));
+            counter += 1;
+        }
+        key
+    }
+
+    /// Derive a key of `len` bytes from `secret` with PBKDF2.
+    pub fn pbkdf2(&self, secret: &[u8], salt: &[u8], iterations: u32, len: uint) -> Vec<u8> {
+        let mut key = Vec::from_elem(len, 0u8);
+        match *self {
+            HmacSha1   => pbkdf2(&mut Hmac::new(Sha1::new(), secret), salt, iterations, key.as_mut_slice()),
+            HmacSha256 => pbkdf2(&mut Hmac::new(Sha256::new(), secret), salt, iterations, key.as_mut_slice()),
+            HmacSha384 => pbkdf2(&mut Hmac::new(Sha384::new(), secret), salt, iterations, key.as_mut_slice()),
+            HmacSha512 => pbkdf2(&mut Hmac::new(Sha512::new(), secret), salt, iterations, key.as_mut_slice())
+        }
+        key
+    }
+}
+
+/// A message authentication code for signed cookies.
+///
+/// Signatures are tagged with the signer which produced them, so that cookies
+/// signed by one signer can still be verified while moving to another.
+pub trait Signer {
+    /// A short name for the algorithm, such as `sha512`, recorded in signatures.
+    ///
+    /// Tags must be unique, and must not contain `$` or `.`.
+    fn tag(&self) -> &'static str;
+
+    /// Authenticate `data` with `key`, returning the raw MAC.
+    fn sign(&self, key: &[u8], data: &[u8]) -> Vec<u8>;
+
+    /// Precompute the state of `key`, to authenticate many values with it.
+    ///
+    /// Returns `None` by default, in which case `sign` is called for each value.
+    fn precompute(&self, _key: &[u8]) -> Option<Box<PrecomputedMac + Send + Sync>> { None }
+}
+
+/// The key state of a `Signer`, precomputed once to authenticate many values.
+pub trait PrecomputedMac {
+    /// Authenticate `data`, returning the raw MAC.
+    fn sign(&self, data: &[u8]) -> Vec<u8>;
+}
+
+impl Signer for HmacDigest {
+    fn tag(&self) -> &'static str {
+        match *self {
+            HmacSha1   => "sha1",
+            HmacSha256 => "sha256",
+            HmacSha384 => "sha384",
+            HmacSha512 => "sha512"
+        }
+    }
+
+    fn sign(&self, key: &[u8], data: &[u8]) -> Vec<u8> { self.hmac(key, data) }
+
+    fn precompute(&self, key: &[u8]) -> Option<Box<PrecomputedMac + Send + Sync>> {
+        Some(match *self {
+            HmacSha1   => box HmacKey::new(Sha1::new(), key) as Box<PrecomputedMac + Send + Sync>,
+            HmacSha256 => box HmacKey::new(Sha256::new(), key) as Box<PrecomputedMac + Send + Sync>,
+            HmacSha384 => box HmacKey::new(Sha384::new(), key) as Box<PrecomputedMac + Send + Sync>,
+            HmacSha512 => box HmacKey::new(Sha512::new(), key) as Box<PrecomputedMac + Send + Sync>
+        })
+    }
+}
+
+// The HMAC key schedule, precomputed as the digest states after the padded keys.
+// Each MAC then clones these states, rather than hashing the key again.
+struct HmacKey<D> {
+    inner: D,
+    outer: D
+}
+
+impl<D: Digest + Clone> HmacKey<D> {
+    fn new(digest: D, key: &[u8]) -> HmacKey<D> {
+        let block_size = digest.block_size();
+        let mut padded = if key.len() > block_size { hash(digest.clone(), key) } else { key.to_vec() };
+        let len = padded.len();
+        padded.grow(block_size - len, 0u8);
+
+        let mut inner = digest.clone();
+        inner.input(padded.iter().map(|&byte| byte ^ 0x36).collect::<Vec<u8>>().as_slice());
+        let mut outer = digest;
+        outer.input(padded.iter().map(|&byte| byte ^ 0x5c).collect::<Vec<u8>>().as_slice());
+        HmacKey { inner: inner, outer: outer }
+    }
+}
+
+impl<D: Digest + Clone> PrecomputedMac for HmacKey<D> {
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let mut inner = self.inner.clone();
+        inner.input(data);
+        let mut inner_hash = Vec::from_elem(inner.output_bytes(), 0u8);
+        inner.result(inner_hash.as_mut_slice());
+        hash(self.outer.clone(), inner_hash.as_slice())
+    }
+}
+
+/// Keyed BLAKE2b, with a 64-byte MAC.
+///
+/// Keys longer than 64 bytes are hashed with BLAKE2b first.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Blake2bMac;
+
+impl Signer for Blake2bMac {
+    fn tag(&self) -> &'static str { "blake2b" }
+
+    fn sign(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        let key = if key.len() > 64 { hash(Blake2b::new(64), key) } else { key.to_vec() };
+        mac(Blake2b::new_keyed(64, key.as_slice()), data)
+    }
+}
+
+fn mac<M: Mac>(mut mac: M, data: &[u8]) -> Vec<u8> {
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+fn hash<D: Digest>(mut digest: D, data: &[u8]) -> Vec<u8> {
+    digest.input(data);
+    let mut hash = Vec::from_elem(digest.output_bytes(), 0u8);
+    digest.result(hash.as_mut_slice());
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serialize::hex::ToHex;
+
+    #[test]
+    fn check_hmac() {
+        // HMAC of key "@zzmp" and message "thung"
+        assert_eq!(HmacSha256.hmac(b"@zzmp", b"thung").as_slice().to_hex(),
+            "e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb".to_string());
+    }
+
+    #[test]
+    fn check_hash() {
+        assert_eq!(HmacSha1.hash(b"abc").as_slice().to_hex(),
+            "a9993e364706816aba3e25717850c26c9cd0d89d".to_string());
+        assert_eq!(HmacSha512.hash(b"").len(), 64);
+    }
+
+    #[test]
+    fn check_hkdf() {
+        // RFC 5869, test case 1
+        let secret = Vec::from_elem(22, 0x0bu8);
+        let salt: Vec<u8> = range(0u8, 13).collect();
+        let info: Vec<u8> = range(0xf0u8, 0xfa).collect();
+        assert_eq!(HmacSha256.hkdf(secret.as_slice(), salt.as_slice(), info.as_slice(), 42).as_slice().to_hex(),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865".to_string());
+    }
+
+    #[test]
+    fn check_precompute() {
+        // Keys longer than the block size are hashed first
+        let long_key = Vec::from_elem(200, 0x61u8);
+        for digest in [HmacSha1, HmacSha256, HmacSha384, HmacSha512].iter() {
+            for &key in [b"@zzmp", b"", long_key.as_slice()].iter() {
+                let precomputed = digest.precompute(key).unwrap();
+                assert_eq!(precomputed.sign(b"thung"), digest.hmac(key, b"thung"));
+                assert_eq!(precomputed.sign(b""), digest.hmac(key, b""));
+            }
+        }
+        assert!(Blake2bMac.precompute(b"@zzmp").is_none());
+    }
+
+    #[test]
+    fn check_signers() {
+        assert_eq!(HmacSha512.sign(b"@zzmp", b"thung").as_slice().to_hex(),
+            "a393a6a5c3886ba00fdcb1ae354d770c77612b351d2115780b9463503daed220\
+             b6c9e4fd7187cc47b8cb78f6e2c488ba7a3c5b0b6da47a70287cfdb3d93317c4".to_string());
+        assert_eq!(Blake2bMac.sign(b"@zzmp", b"thung").as_slice().to_hex(),
+            "9d0eff6c8ff1726db28d48c1a55c43b2a5a713be39c71a15595d7615c77dbbd5\
+             a9338860733a74e4a94d1a8bec3bcfe1bdcc0267d6f8e51ed2c7880684408be1".to_string());
+        assert_eq!(HmacSha384.tag(), "sha384");
+        assert_eq!(Blake2bMac.tag(), "blake2b");
+    }
+}
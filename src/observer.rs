@@ -0,0 +1,123 @@
+//! Observers - hooks and metrics for the cookies a parser accepts or removes
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUint, Relaxed};
+use iron::Request;
+use super::{Rejection, Oversized, Verification, InvalidSignature, Unsigned, Malformed, Expired, Verified};
+use super::Unverifiable;
+
+/// Notified of each cookie parsed by a `CookieParser`, such as to alert on tampering.
+///
+/// Each method is given the request the cookie was received with.
+/// All methods do nothing by default.
+pub trait CookieObserver {
+    /// Called for each cookie kept by the parser.
+    fn accepted(&self, _req: &Request, _name: &str) {}
+
+    /// Called for each cookie rejected by validation, including those which are oversized.
+    ///
+    /// The name is as received, before decoding.
+    fn rejected(&self, _req: &Request, _name: &str, _reason: &Rejection) {}
+
+    /// Called for each cookie removed because its signature could not be verified,
+    /// or was missing when required.
+    fn unverified(&self, _req: &Request, _name: &str, _outcome: &Verification) {}
+}
+
+impl<O: CookieObserver> CookieObserver for Arc<O> {
+    fn accepted(&self, req: &Request, name: &str) { (**self).accepted(req, name) }
+
+    fn rejected(&self, req: &Request, name: &str, reason: &Rejection) {
+        (**self).rejected(req, name, reason)
+    }
+
+    fn unverified(&self, req: &Request, name: &str, outcome: &Verification) {
+        (**self).unverified(req, name, outcome)
+    }
+}
+
+/// A `CookieObserver` counting the cookies parsed, by outcome.
+///
+/// Share it with the parser through an `Arc`, and read the `counts` from your monitoring:
+/// `parser.observer(metrics.clone())`.
+pub struct CookieMetrics {
+    accepted: AtomicUint,
+    rejected: AtomicUint,
+    oversized: AtomicUint,
+    invalid_signature: AtomicUint,
+    unsigned: AtomicUint,
+    malformed: AtomicUint,
+    expired: AtomicUint
+}
+
+/// The counts of a `CookieMetrics`, since it was created.
+#[deriving(Clone, PartialEq, Show)]
+pub struct CookieCounts {
+    /// Cookies kept by the parser.
+    pub accepted: uint,
+    /// Cookies rejected by validation, other than for their size.
+    pub rejected: uint,
+    /// Cookies rejected for their size.
+    pub oversized: uint,
+    /// Cookies removed for an invalid signature.
+    pub invalid_signature: uint,
+    /// Unsigned cookies removed by a signed parser.
+    pub unsigned: uint,
+    /// Cookies removed for a malformed signature or value.
+    pub malformed: uint,
+    /// Cookies removed for an expired signature.
+    pub expired: uint
+}
+
+impl CookieMetrics {
+    /// Create metrics with every count at zero.
+    pub fn new() -> CookieMetrics {
+        CookieMetrics {
+            accepted: AtomicUint::new(0),
+            rejected: AtomicUint::new(0),
+            oversized: AtomicUint::new(0),
+            invalid_signature: AtomicUint::new(0),
+            unsigned: AtomicUint::new(0),
+            malformed: AtomicUint::new(0),
+            expired: AtomicUint::new(0)
+        }
+    }
+
+    /// Read the current counts.
+    pub fn counts(&self) -> CookieCounts {
+        CookieCounts {
+            accepted: self.accepted.load(Relaxed),
+            rejected: self.rejected.load(Relaxed),
+            oversized: self.oversized.load(Relaxed),
+            invalid_signature: self.invalid_signature.load(Relaxed),
+            unsigned: self.unsigned.load(Relaxed),
+            malformed: self.malformed.load(Relaxed),
+            expired: self.expired.load(Relaxed)
+        }
+    }
+}
+
+impl CookieObserver for CookieMetrics {
+    fn accepted(&self, _req: &Request, _name: &str) {
+        self.accepted.fetch_add(1, Relaxed);
+    }
+
+    fn rejected(&self, _req: &Request, _name: &str, reason: &Rejection) {
+        match *reason {
+            Oversized => self.oversized.fetch_add(1, Relaxed),
+            _         => self.rejected.fetch_add(1, Relaxed)
+        };
+    }
+
+    fn unverified(&self, _req: &Request, _name: &str, outcome: &Verification) {
+        match *outcome {
+            InvalidSignature => self.invalid_signature.fetch_add(1, Relaxed),
+            Unsigned         => self.unsigned.fetch_add(1, Relaxed),
+            Malformed        => self.malformed.fetch_add(1, Relaxed),
+            Expired          => self.expired.fetch_add(1, Relaxed),
+            Verified         => self.accepted.fetch_add(1, Relaxed),
+            // Not reported by `CookieParser`, as it is not evidence of tampering
+            Unverifiable     => return
+        };
+    }
+}
@@ -1,12 +1,20 @@
 //! Parsing functionality - get cookie data
 
 use std::collections::treemap::TreeMap;
-use url::lossy_utf8_percent_decode;
+use std::collections::hashmap::{HashMap, HashSet};
+use std::sync::Arc;
 use serialize::json;
 use serialize::json::{Json, Null};
 use iron::{Request, Response, Middleware, Status, Continue};
-use super::Cookie;
-use crypto::util::fixed_time_eq;
+use super::{Cookie, Encoding, Percent, Raw, SignatureFormat, HexSignature, ValueCodec, Signer, HmacSha256};
+use super::{MasterKey, SecretError, CookieObserver, LazyCookie};
+use lazy::take_checked;
+use secret::check_secret;
+use keys::SecretKeys;
+use cookie::keyed_cookie;
+
+// The `j:` of plain values which would otherwise be read as JSON, escaped by `SetCookie`
+pub static ESCAPED_JSON: &'static str = "%6A%3A";
 
 /// The cookie parsing `Middleware`.
 ///
@@ -15,9 +23,106 @@ use crypto::util::fixed_time_eq;
 /// This middleware should be linked (added to the `Chain`)
 /// before any other middleware using cookies, or the parsed cookie
 /// will not be available to that middleware.
+///
+/// Clones of a parser share its configuration.
 #[deriving(Clone)]
 pub struct CookieParser {
-    secret: Option<String>
+    config: Arc<ParserConfig>
+}
+
+// The configuration of a `CookieParser`, shared by its clones and by each request's `LazyCookie`
+#[deriving(Clone)]
+struct ParserConfig {
+    keys: Arc<SecretKeys>,
+    duplicates: DuplicatePolicy,
+    validation: Validation,
+    encoding: Encoding,
+    signature_format: SignatureFormat,
+    signer: Arc<Box<Signer + Send + Sync>>,
+    accepted: Vec<Arc<Box<Signer + Send + Sync>>>,
+    codec: Option<Arc<Box<ValueCodec + Send + Sync>>>,
+    max_size: Option<uint>,
+    lazy: bool,
+    observers: Vec<Arc<Box<CookieObserver + Send + Sync>>>
+}
+
+/// How to resolve a cookie name which is received more than once.
+///
+/// Browsers send every cookie matching a request, so two cookies
+/// with the same name but different paths or domains will both be sent.
+/// A cookie set by a sibling subdomain can shadow your own this way.
+///
+/// All occurrences are always kept in `Cookie.pairs`; the policy
+/// decides which value is stored in `Cookie.map` and `Cookie.json`.
+#[deriving(Clone, PartialEq, Show)]
+pub enum DuplicatePolicy {
+    /// Keep the first occurrence, as recommended by RFC 6265.
+    ///
+    /// Browsers send cookies with longer paths first. This is the default.
+    FirstWins,
+    /// Keep the last occurrence.
+    LastWins,
+    /// Keep no value for a name received more than once.
+    ///
+    /// This is recommended for signed cookies, where any duplicate
+    /// is likely to have been tossed in by another origin.
+    RejectDuplicates
+}
+
+/// How strictly incoming cookies are validated.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Validation {
+    /// Accept any cookie, replacing invalid UTF-8 with `U+FFFD`.
+    ///
+    /// This is the default, and suits public sites which must
+    /// cope with whatever cookies browsers and other services send.
+    Lenient,
+    /// Reject cookies which do not follow the RFC 6265 grammar.
+    ///
+    /// Names must be RFC 2616 tokens, values must be `cookie-octet`s,
+    /// and both must decode to valid UTF-8.
+    Strict
+}
+
+/// The reason a cookie was rejected by the parser.
+///
+/// Rejected cookies are recorded, with their reason, in `Cookie.rejected`.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Rejection {
+    /// The name is not an RFC 2616 token.
+    InvalidName,
+    /// The value contains characters other than `cookie-octet`s.
+    InvalidValue,
+    /// The name or value could not be decoded, or, when `Strict`,
+    /// does not decode to valid UTF-8.
+    InvalidEncoding,
+    /// The name and value are longer than the parser's `max_size`.
+    Oversized
+}
+
+/// The outcome of verifying the signature of an incoming cookie.
+///
+/// Outcomes are recorded for every cookie which is not rejected, in `Cookie.outcomes`.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Verification {
+    /// The cookie was signed by us, or decoded by the parser's codec.
+    Verified,
+    /// The signature is not ours, or the cookie was meant for another name.
+    ///
+    /// This is likely a cookie which has been tampered with.
+    InvalidSignature,
+    /// The cookie is not signed.
+    ///
+    /// Unsigned cookies are kept only by parsers without a secret.
+    Unsigned,
+    /// The cookie is marked as signed, but the signature or value is malformed.
+    Malformed,
+    /// The signature is valid, but has expired, as reported by the parser's codec.
+    Expired,
+    /// The cookie is signed, but the parser has no secret to verify it with.
+    ///
+    /// The cookie is removed, but this is not evidence of tampering.
+    Unverifiable
 }
 
 impl CookieParser {
@@ -26,102 +131,540 @@ impl CookieParser {
     /// This instance will parse both RFC 6265-styled cookies:
     /// `key=value; key=value;`
     /// and json-styled cookies, as set with `res.set_json_cookie(...)`.
-    pub fn new() -> CookieParser { CookieParser{ secret: None} }
+    pub fn new() -> CookieParser { CookieParser::with_secret(None) }
 
     /// Create a cookie parser with secret, for signed cookies.
     ///
     /// This instance will parse any cookies that have been signed by
     /// you, or that are unsigned. It will not parse those cookies signed by others.
     ///
+    /// The secret must pass `check_secret`, or anyone could forge your cookies.
+    /// Use `secret_file` to generate a strong secret, and keep it in a key file.
+    ///
     /// Otherwise, it will behave exactly like that produced by `new`.
-    pub fn signed(secret: String) -> CookieParser { CookieParser{ secret: Some(secret) } }
+    pub fn signed(secret: String) -> Result<CookieParser, SecretError> {
+        try!(check_secret(secret.as_slice()));
+        Ok(CookieParser::with_secret(Some(secret)))
+    }
+
+    /// Create a cookie parser with secret, for signed cookies, without checking the secret.
+    ///
+    /// This is for secrets shared with other systems, whose strength is out of your hands.
+    /// Otherwise, prefer `signed`.
+    pub fn signed_unchecked(secret: String) -> CookieParser { CookieParser::with_secret(Some(secret)) }
+
+    /// Create a cookie parser for signed cookies, with keys derived from `master`.
+    ///
+    /// Each cookie name is signed with its own key, so that no signing key
+    /// reveals the master secret, or the keys for other names or purposes.
+    /// Cookies signed this way cannot be read by other frameworks.
+    ///
+    /// Otherwise, it will behave exactly like that produced by `signed`.
+    pub fn derived(master: MasterKey) -> CookieParser {
+        let mut parser = CookieParser::with_secret(None);
+        parser.config_mut().keys = Arc::new(SecretKeys::derived(master));
+        parser
+    }
+
+    fn with_secret(secret: Option<String>) -> CookieParser {
+        let keys = SecretKeys::new(secret.as_ref().map(|secret| secret.as_slice()));
+        CookieParser { config: Arc::new(ParserConfig {
+            keys: Arc::new(keys),
+            duplicates: FirstWins,
+            validation: Lenient,
+            encoding: Percent,
+            signature_format: HexSignature,
+            signer: Arc::new(box HmacSha256 as Box<Signer + Send + Sync>),
+            accepted: Vec::new(),
+            codec: None,
+            max_size: None,
+            lazy: false,
+            observers: Vec::new()
+        }) }.precompute()
+    }
+
+    // The configuration, copied first if it is shared
+    fn config_mut(&mut self) -> &mut ParserConfig {
+        self.config.make_unique()
+    }
+
+    // Precompute the key state of every signer, once for all requests
+    fn precompute(mut self) -> CookieParser {
+        let keys = {
+            let mut signers = vec![&self.config.signer];
+            signers.extend(self.config.accepted.iter());
+            self.config.keys.precompute(signers.as_slice())
+        };
+        self.config_mut().keys = Arc::new(keys);
+        self
+    }
+
+    /// Set the policy used to resolve cookie names received more than once.
+    ///
+    /// Defaults to `FirstWins`.
+    pub fn duplicates(mut self, policy: DuplicatePolicy) -> CookieParser {
+        self.config_mut().duplicates = policy;
+        self
+    }
+
+    /// Set how strictly incoming cookies are validated.
+    ///
+    /// Defaults to `Lenient`.
+    pub fn validation(mut self, validation: Validation) -> CookieParser {
+        self.config_mut().validation = validation;
+        self
+    }
+
+    /// Set the encoding of cookie names and values.
+    ///
+    /// This encoding will also be used to set cookies through the parsed `Cookie`.
+    /// Defaults to `Percent`.
+    pub fn encoding(mut self, encoding: Encoding) -> CookieParser {
+        self.config_mut().encoding = encoding;
+        self
+    }
+
+    /// Set the text format of cookie signatures.
+    ///
+    /// Use `ExpressSignature` to share signed cookies with Express applications.
+    /// This format will also be used to set cookies through the parsed `Cookie`.
+    /// Defaults to `HexSignature`.
+    pub fn signature_format(mut self, format: SignatureFormat) -> CookieParser {
+        self.config_mut().signature_format = format;
+        self
+    }
+
+    /// Sign cookies with `signer`, such as `HmacSha512`.
+    ///
+    /// Signatures record the signer's tag. Cookies signed by any other signer
+    /// will not be parsed, unless that signer is also given to `accept`.
+    /// This signer will also be used to set cookies through the parsed `Cookie`.
+    /// Defaults to `HmacSha256`.
+    pub fn signer<S: Signer + Send + Sync>(mut self, signer: S) -> CookieParser {
+        self.config_mut().signer = Arc::new(box signer as Box<Signer + Send + Sync>);
+        self.precompute()
+    }
+
+    /// Also accept cookies signed by `signer`.
+    ///
+    /// This allows a move to a new signer without invalidating cookies
+    /// signed by the old one: `.signer(HmacSha512).accept(HmacSha256)`.
+    pub fn accept<S: Signer + Send + Sync>(mut self, signer: S) -> CookieParser {
+        self.config_mut().accepted.push(Arc::new(box signer as Box<Signer + Send + Sync>));
+        self.precompute()
+    }
+
+    /// Decode every cookie with `codec`, such as a `RailsCodec`,
+    /// in place of our own signatures.
+    ///
+    /// Cookies which the codec cannot verify will not be parsed.
+    /// This codec will also be used to set cookies through the parsed `Cookie`.
+    pub fn codec<C: ValueCodec + Send + Sync>(mut self, codec: C) -> CookieParser {
+        self.config_mut().codec = Some(Arc::new(box codec as Box<ValueCodec + Send + Sync>));
+        self
+    }
+
+    /// Reject cookies whose name and value, as received, are longer than `bytes`.
+    ///
+    /// Browsers are only required to store cookies of 4096 bytes, including attributes.
+    /// Defaults to no limit.
+    pub fn max_size(mut self, bytes: uint) -> CookieParser {
+        self.config_mut().max_size = Some(bytes);
+        self
+    }
+
+    /// Notify `observer` of every cookie parsed, such as a `CookieMetrics`.
+    ///
+    /// Observers are called in the order they were added, once each cookie has been parsed.
+    pub fn observer<O: CookieObserver + Send + Sync>(mut self, observer: O) -> CookieParser {
+        self.config_mut().observers.push(Arc::new(box observer as Box<CookieObserver + Send + Sync>));
+        self
+    }
+
+    /// Parse cookies only when a handler first asks for them.
+    ///
+    /// A `LazyCookie` is stored in the alloy in place of a `Cookie`. It can find
+    /// a single cookie, verifying only that cookie's signature, or parse them all.
+    /// Observers are notified of only the cookies parsed, once the response
+    /// leaves the parser, on `exit`.
+    pub fn lazy(mut self) -> CookieParser {
+        self.config_mut().lazy = true;
+        self
+    }
+
+    /// Parse a Cookie header, as received with a request.
+    ///
+    /// This is the `Cookie` stored in the alloy by a parser which is not `lazy`.
+    pub fn parse(&self, header: &str) -> Cookie {
+        let mut new_cookie = new_cookie(self);
+        let mut rejected = Vec::new();
+        let mut outcomes = Vec::new();
+        let decoded: Vec<(String, String, bool)> =
+            header
+                .split(';')
+                // Split each cookie-pair, ignoring those which are malformed
+                .filter_map(parse_pair)
+                // Validate and decode, filtering cookies not signed by us
+                .filter_map(|(key, val)| {
+                    let decoded = match self.config.max_size {
+                        Some(max_size) if key.len() + val.len() > max_size => Err(Oversized),
+                        _                                                  => {
+                            decode_pair(key, val, &self.config.validation, &new_cookie)
+                        }
+                    };
+                    let escaped = is_escaped_json(val, &new_cookie);
+                    match decoded {
+                        Ok((key, decoded, outcome)) => {
+                            outcomes.push((key.clone(), outcome));
+                            decoded.map(|decoded| (key, decoded, escaped))
+                        },
+                        Err(reason)             => {
+                            rejected.push((key.to_string(), reason));
+                            None
+                        }
+                    } })
+                .collect();
+
+        // Initialize an empty json object.
+        let mut new_json = json::Object(TreeMap::new());
+        {
+            // Count occurrences, so that duplicates can be resolved by policy.
+            // Names are borrowed from `decoded`, whose pairs are kept in the cookie.
+            let mut counts = HashMap::new();
+            if self.config.duplicates == RejectDuplicates {
+                for &(ref key, _, _) in decoded.iter() {
+                    *counts.find_or_insert(key.as_slice(), 0u) += 1;
+                }
+            }
+
+            let mut seen = HashSet::new();
+            for cookie in decoded.iter() {
+                let &(ref key, ref val, _) = cookie;
+                let keep = match self.config.duplicates {
+                    FirstWins        => seen.insert(key.as_slice()),
+                    LastWins         => true,
+                    RejectDuplicates => *counts.get(&key.as_slice()) == 1
+                };
+                // Move json cookies into a separate container
+                if keep && parse_json(cookie, &mut new_json) {
+                    new_cookie.map.insert(key.clone(), val.clone());
+                }
+            }
+        }
+
+        new_cookie.json = new_json;
+        new_cookie.pairs = decoded.into_iter().map(|(key, val, _)| (key, val)).collect();
+        new_cookie.rejected = rejected;
+        new_cookie.outcomes = outcomes;
+        new_cookie
+    }
+
+    // Notify observers of every cookie accepted, rejected or removed
+    fn notify(&self, req: &Request, cookie: &Cookie) {
+        for observer in self.config.observers.iter() {
+            for &(ref key, ref reason) in cookie.rejected.iter() {
+                observer.rejected(req, key.as_slice(), reason);
+            }
+            for &(ref key, ref outcome) in cookie.outcomes.iter() {
+                match *outcome {
+                    Verified                   => observer.accepted(req, key.as_slice()),
+                    Unsigned if !cookie.signed => observer.accepted(req, key.as_slice()),
+                    // Observers are alerted only to cookies which could be verified
+                    Unverifiable               => (),
+                    _                          => observer.unverified(req, key.as_slice(), outcome)
+                }
+            }
+        }
+    }
 }
 
 impl Middleware for CookieParser {
     /// Parse the cookie received in the HTTP header.
     ///
-    /// This will parse the body of a cookie into the alloy, under type `Cookie`.
+    /// This will parse the body of a cookie into the alloy, under type `Cookie`,
+    /// or, for a `lazy` parser, store a `LazyCookie` to be parsed later.
     fn enter(&mut self, req: &mut Request, _res: &mut Response) -> Status {
-        // Initialize a cookie. This will store parsed cookies and generate signatures.
-        let mut new_cookie = Cookie::new(self.secret.clone());
-
-        match req.headers.extensions.find_mut(&"Cookie".to_string()) {
-            Some(cookies) => {
-                // Initialize an empty json object.
-                let mut new_json = json::Object(TreeMap::new());
-                new_cookie.map =
-                    cookies
-                        .as_slice()
-                        .split(';')
-                        // Decode from uri component encoding
-                        .map(|substr| {
-                            let vec: Vec<&str> = substr.splitn('=', 1).collect();
-                            let key = from_rfc_compliant(vec[0]);
-                            let val = from_rfc_compliant(vec[1]);
-                            (key, val) })
-                        // Check for signed cookies, and filter those not signed by us
-                        .filter_map(|cookie| strip_signature(cookie, &new_cookie))
-                        // Move json cookies into a separate container
-                        .filter(|cookie| parse_json(cookie, &mut new_json))
-                        .collect();
-
-                // This cannot be inserted via iterators because strip_signature
-                // is already borrowing new_cookie.
-                new_cookie.json = new_json;
-            },
-            None => ()
+        if self.config.lazy {
+            let header = req.headers.extensions.find(&"Cookie".to_string()).map(|header| header.clone());
+            req.alloy.insert(LazyCookie::new(self.clone(), header));
+            return Continue
         }
+        let new_cookie = match req.headers.extensions.find(&"Cookie".to_string()) {
+            Some(header) => self.parse(header.as_slice()),
+            None         => new_cookie(self)
+        };
+        self.notify(req, &new_cookie);
         req.alloy.insert(new_cookie);
         Continue
     }
+
+    /// Notify observers of the cookies a `lazy` parser has parsed.
+    fn exit(&mut self, req: &mut Request, _res: &mut Response) -> Status {
+        if self.config.lazy && !self.config.observers.is_empty() {
+            let checked = req.alloy.find_mut::<LazyCookie>().map(take_checked);
+            match checked {
+                Some(cookie) => self.notify(req, &cookie),
+                None         => ()
+            }
+        }
+        Continue
+    }
 }
 
-fn from_rfc_compliant(string: &str) -> String {
-    lossy_utf8_percent_decode(
-        string
-            .chars()
-            .skip_while(is_whitespace)
-            .collect::<String>()
-            .as_bytes()
-    )
-}
-
-fn is_whitespace(c: &char) -> bool {
-    match *c {
-        ' '|'\r'|'\t'|'\n' => true,
-        _                  => false
-    }
-}
-
-fn strip_signature((key, val): (String, String), signer: &Cookie) -> Option<(String, String)> {
-    if val.len() > 2 && val.as_slice().slice(0, 2) == "s:" {
-        if !signer.signed { return None }
-        // Extract the signature (in hex), appended onto the cookie after `.`
-        return regex!(r"\.[^\.]*$").find(val.as_slice())
-            // If it was signed by us, clear the signature
-            .and_then(|(beg, end)| {
-                signer.sign(&val.as_slice().slice(2, beg).to_string())
-                    // We need to maintain access to (beg, end), so we chain the signature
-                    .and_then(|signature| {
-                        // If the signature is valid, strip it
-                         if fixed_time_eq(val.as_slice().slice(beg + 1, end).as_bytes(), signature.as_bytes()) {
-                            // key must be cloned to move out of the closure capture
-                            Some((key.clone(), val.as_slice().slice(2, beg).to_string()))
-                        // Else, remove the cookie
-                        } else {
-                            None
-                        }
-                    })
-            })
+// Initialize a cookie with the parser's configuration.
+// This will store parsed cookies and generate signatures.
+pub fn new_cookie(parser: &CookieParser) -> Cookie {
+    let mut cookie = keyed_cookie(parser.config.keys.clone(), parser.config.signer.clone());
+    cookie.encoding = parser.config.encoding.clone();
+    cookie.signature_format = parser.config.signature_format.clone();
+    cookie.accepted = parser.config.accepted.clone();
+    cookie.codec = parser.config.codec.clone();
+    cookie
+}
+
+// Parse and verify only the cookie `name` from a Cookie header,
+// returning the value which would be stored in `Cookie.map`.
+// Its rejections and verification outcomes are added to `rejected` and `outcomes`.
+pub fn parse_one(parser: &CookieParser, signer: &Cookie, header: &str, name: &str,
+                 rejected: &mut Vec<(String, Rejection)>,
+                 outcomes: &mut Vec<(String, Verification)>) -> Option<String> {
+    let values: Vec<(String, bool)> = header
+        .split(';')
+        .filter_map(parse_pair)
+        // Only the cookies named `name` are decoded and verified
+        .filter(|&(key, _)| is_named(key, name, &signer.encoding))
+        .filter_map(|(key, val)| {
+            let decoded = match parser.config.max_size {
+                Some(max_size) if key.len() + val.len() > max_size => Err(Oversized),
+                _                                                  => decode_pair(key, val, &parser.config.validation, signer)
+            };
+            let escaped = is_escaped_json(val, signer);
+            match decoded {
+                Ok((key, decoded, outcome)) => {
+                    outcomes.push((key, outcome));
+                    decoded.map(|decoded| (decoded, escaped))
+                },
+                Err(reason)             => {
+                    rejected.push((key.to_string(), reason));
+                    None
+                }
+            } })
+        .collect();
+    let value = match parser.config.duplicates {
+        FirstWins                            => values.as_slice().head(),
+        // Later JSON cookies are kept apart, and do not replace the value in `Cookie.map`
+        LastWins                             => values.iter().filter(|&&(ref val, escaped)| !is_json(val.as_slice(), escaped)).last(),
+        RejectDuplicates if values.len() == 1 => values.as_slice().head(),
+        RejectDuplicates                     => None
+    };
+    match value {
+        // JSON cookies are not stored in `Cookie.map`
+        Some(&(ref val, escaped)) if is_json(val.as_slice(), escaped) => None,
+        Some(&(ref val, _))                                          => Some(val.clone()),
+        None                                                         => None
+    }
+}
+
+// True if a decoded value is JSON, unless its `j:` prefix was escaped when it was set
+pub fn is_json(val: &str, escaped: bool) -> bool {
+    !escaped && val.len() > 2 && val.starts_with("j:")
+}
+
+// True if the encoded value, which may be signed, begins with an escaped `j:`.
+// Codecs and the `Raw` encoding write values unchanged, so never escape them.
+pub fn is_escaped_json(val: &str, signer: &Cookie) -> bool {
+    match (&signer.codec, strip_signature(val)) {
+        (&None, Some((val, _))) => unescape_json(val, &signer.encoding).is_some(),
+        _                       => false
+    }
+}
+
+// Strip the escaped `j:` with which `SetCookie` prefixes plain values beginning `j:`
+fn unescape_json<'a>(val: &'a str, encoding: &Encoding) -> Option<&'a str> {
+    if *encoding != Raw && val.starts_with(ESCAPED_JSON) {
+        Some(val.slice_from(ESCAPED_JSON.len()))
+    } else {
+        None
+    }
+}
+
+fn is_named(key: &str, name: &str, encoding: &Encoding) -> bool {
+    if encoding.is_unescaped_name(key) {
+        key == name
+    } else {
+        encoding.decode_name(key).as_slice() == name.as_bytes()
+    }
+}
+
+/// Split a cookie-pair into its name and value.
+///
+/// This follows the name-value-pair steps of the algorithm in
+/// [RFC 6265, section 5.2](http://tools.ietf.org/html/rfc6265#section-5.2):
+/// pairs without a `=` or with an empty name are ignored, and leading and
+/// trailing whitespace is removed from both name and value.
+///
+/// Values wrapped in DQUOTEs, as allowed by the `cookie-value` grammar
+/// of [section 4.1.1](http://tools.ietf.org/html/rfc6265#section-4.1.1), are unquoted.
+pub fn parse_pair<'a>(pair: &'a str) -> Option<(&'a str, &'a str)> {
+    let (name, value) = match pair.find('=') {
+        Some(i) => (pair.slice_to(i), pair.slice_from(i + 1)),
+        None    => return None
+    };
+    let name = name.trim_chars(is_whitespace);
+    if name.is_empty() { return None }
+    Some((name, unquote(value.trim_chars(is_whitespace))))
+}
+
+// Split a Set-Cookie header into its cookie-pair, as `parse_pair` splits it, and its attributes,
+// each a name and the value after any `=`, trimmed as described by RFC 6265, section 5.2.
+pub fn split_set_cookie<'a>(header: &'a str) -> Option<((&'a str, &'a str), Vec<(&'a str, Option<&'a str>)>)> {
+    let mut parts = header.split(';');
+    let pair = match parts.next().and_then(parse_pair) {
+        Some(pair) => pair,
+        None       => return None
+    };
+    let attributes = parts.map(|attribute| match attribute.find('=') {
+        Some(i) => (attribute.slice_to(i).trim(), Some(attribute.slice_from(i + 1).trim())),
+        None    => (attribute.trim(), None)
+    }).collect();
+    Some((pair, attributes))
+}
+
+fn unquote<'a>(value: &'a str) -> &'a str {
+    if value.len() >= 2 && value.starts_with("\"") && value.ends_with("\"") {
+        value.slice(1, value.len() - 1)
+    } else {
+        value
+    }
+}
+
+// Validate and decode a cookie-pair, checking for signed cookies.
+//
+// Returns the decoded name, the value unless it is not signed by us or should have been,
+// and the outcome of verifying its signature.
+pub fn decode_pair(key: &str, val: &str, validation: &Validation, signer: &Cookie)
+    -> Result<(String, Option<String>, Verification), Rejection> {
+    if *validation == Strict {
+        if !key.chars().all(is_token) { return Err(InvalidName) }
+        if !val.chars().all(is_cookie_octet) { return Err(InvalidValue) }
+    }
+    let key = if signer.encoding.is_unescaped_name(key) {
+        key.to_string()
+    } else {
+        try!(to_utf8(Some(signer.encoding.decode_name(key)), validation))
+    };
+    match signer.codec {
+        Some(ref codec) => return Ok(match codec.verify(key.as_slice(), val) {
+            Ok(val)      => (key, Some(val), Verified),
+            Err(outcome) => (key, None, outcome)
+        }),
+        None            => ()
+    }
+    // The signature is stripped before decoding, as it is appended to the encoded value
+    match strip_signature(val) {
+        Some((val, signature)) => {
+            let val = match unescape_json(val, &signer.encoding) {
+                // The rest of an escaped value is encoded, as though it were the whole value
+                Some(rest)                               => {
+                    "j:".to_string().append(try!(to_utf8(signer.encoding.decode(rest), validation)).as_slice())
+                },
+                None if signer.encoding.is_unescaped(val) => val.to_string(),
+                None                                      => try!(to_utf8(signer.encoding.decode(val), validation))
+            };
+            // Signatures may be percent-encoded, as they are by Express
+            let decoded;
+            let signature = match signature {
+                Some(signature) if !signer.encoding.is_unescaped_name(signature) => {
+                    decoded = String::from_utf8_lossy(signer.encoding.decode_name(signature).as_slice()).into_string();
+                    Some(decoded.as_slice())
+                },
+                signature                                                         => signature
+            };
+            let (val, outcome) = check_signature(key.as_slice(), val, signature, signer);
+            Ok((key, val, outcome))
+        },
+        // Signed values missing a signature are removed
+        None                   => Ok((key, None, Malformed))
+    }
+}
+
+// Convert decoded bytes to a `String`, in place unless they are invalid UTF-8
+fn to_utf8(bytes: Option<Vec<u8>>, validation: &Validation) -> Result<String, Rejection> {
+    match bytes {
+        Some(bytes) => match (String::from_utf8(bytes), validation) {
+            (Ok(string), _)        => Ok(string),
+            (Err(bytes), &Lenient) => Ok(String::from_utf8_lossy(bytes.as_slice()).into_string()),
+            (Err(_), &Strict)      => Err(InvalidEncoding)
+        },
+        None        => Err(InvalidEncoding)
+    }
+}
+
+// token, as defined by RFC 2616, section 2.2
+fn is_token(c: char) -> bool {
+    match c {
+        '('|')'|'<'|'>'|'@'|','|';'|':'|'\\'|'"'|'/'|'['|']'|'?'|'='|'{'|'}' => false,
+        '\x21'..'\x7e' => true,
+        _              => false
+    }
+}
+
+// cookie-octet, as defined by RFC 6265, section 4.1.1
+fn is_cookie_octet(c: char) -> bool {
+    match c {
+        '\x21' | '\x23'..'\x2b' | '\x2d'..'\x3a' | '\x3c'..'\x5b' | '\x5d'..'\x7e' => true,
+        _                                                                  => false
+    }
+}
+
+// WSP, as defined by RFC 5234
+fn is_whitespace(c: char) -> bool {
+    match c {
+        ' '|'\t' => true,
+        _        => false
     }
-    match signer.signed {
-        true => None,
-        false => Some((key, val))
+}
+
+// Split a signed value, `s:value.signature`, into its value and signature.
+//
+// Returns `None` for signed values without a signature.
+fn strip_signature<'a>(val: &'a str) -> Option<(&'a str, Option<&'a str>)> {
+    // Express percent-encodes the `:` of its prefix
+    let prefix = if val.starts_with("s:") {
+        2
+    } else if val.starts_with("s%3A") || val.starts_with("s%3a") {
+        4
+    } else {
+        return Some((val, None))
+    };
+    if val.len() > prefix {
+        // Extract the signature, appended onto the cookie after `.`
+        return val.rfind('.').map(|dot| (val.slice(prefix, dot), Some(val.slice_from(dot + 1))))
     }
+    Some((val, None))
 }
 
-fn parse_json(&(ref key, ref val): &(String, String), json: &mut Json) -> bool {
-    if val.len() > 2 && val.as_slice().slice(0, 2) == "j:" {
+fn check_signature(key: &str, val: String, signature: Option<&str>, signer: &Cookie) -> (Option<String>, Verification) {
+    match signature {
+        // Without a secret, signatures cannot be checked either way
+        Some(_) if !signer.signed => (None, Unverifiable),
+        Some(signature) => {
+            // If it was signed by us, keep the value
+            match signer.verify(key, &val, signature) {
+                true  => (Some(val), Verified),
+                // Else, remove the cookie
+                false => (None, InvalidSignature)
+            }
+        },
+        None            => match signer.signed {
+            true  => (None, Unsigned),
+            false => (Some(val), Unsigned)
+        }
+    }
+}
+
+fn parse_json(&(ref key, ref val, escaped): &(String, String, bool), json: &mut Json) -> bool {
+    if is_json(val.as_slice(), escaped) {
         match *json {
             json::Object(ref mut root) => {
                 root.insert(key.clone(),
@@ -140,22 +683,31 @@ fn parse_json(&(ref key, ref val): &(String, String), json: &mut Json) -> bool {
 #[cfg(test)]
 mod test {
     use std::collections::{HashMap, TreeMap};
-    use iron::{Request, Middleware};
+    use std::sync::{Arc, Mutex};
+    use iron::{Request, Response, Middleware};
+    use observer::{CookieObserver, CookieMetrics, CookieCounts};
     use test::mock::{request, response};
     use super::*;
     use super::super::cookie::*;
-    use serialize::json::{Object, String};
+    use super::super::codec::*;
+    use serialize::json;
+    use serialize::json::{Json, Object, String, Null};
+    use quickcheck::{quickcheck, TestResult};
 
     // Parse a given `String` as an HTTP Cookie header, using the CookieParser middleware,
     // and return the cookie stored in the alloy by that middleware
     fn get_cookie_request(secret: Option<String>, cookie: String) -> Request {
-        let mut req = request::new(::http::method::Get, "localhost:3000");
-        req.headers.extensions.insert("Cookie".to_string(), cookie);
-        let mut signer = match secret {
-            Some(s) => CookieParser::signed(s),
+        let signer = match secret {
+            Some(s) => CookieParser::signed_unchecked(s),
             None => CookieParser::new()
         };
-        signer.enter(&mut req, &mut response::new());
+        get_parser_request(signer, cookie.as_slice())
+    }
+
+    fn get_parser_request(mut parser: CookieParser, cookie: &str) -> Request {
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        req.headers.extensions.insert("Cookie".to_string(), cookie.to_string());
+        parser.enter(&mut req, &mut response::new());
         req
     }
 
@@ -217,4 +769,505 @@ mod test {
         let root = Object(root_map);
         assert_eq!(cookie.json, root); // FIXME
     }
+
+    #[test]
+    fn check_multibyte_values() {
+        // Found by fuzzing: checking for the `j:` prefix must not split a multibyte character
+        let header = "thing=a€b; other=a%E2%82%ACb";
+        let parser = CookieParser::new();
+        let cookie = parser.parse(header);
+        assert_eq!(cookie.get("thing"), Some(&"a€b".to_string()));
+        assert_eq!(cookie.get("other"), Some(&"a€b".to_string()));
+        let (mut rejected, mut outcomes) = (Vec::new(), Vec::new());
+        assert_eq!(parse_one(&parser, &new_cookie(&parser), header, "thing", &mut rejected, &mut outcomes),
+                   Some("a€b".to_string()));
+    }
+
+    // Parse a given `String` as an HTTP Cookie header, resolving duplicates with `policy`
+    #[test]
+    fn check_duplicates() {
+        let header = "session=first; other=thing; session=second".to_string();
+
+        let cookie_request = get_parser_request(CookieParser::new().duplicates(FirstWins), header.as_slice());
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.get("session"), Some(&"first".to_string()));
+        assert_eq!(cookie.get_all("session"), vec![&"first".to_string(), &"second".to_string()]);
+        assert_eq!(cookie.pairs, vec![("session".to_string(), "first".to_string()),
+                                      ("other".to_string(), "thing".to_string()),
+                                      ("session".to_string(), "second".to_string())]);
+
+        let cookie_request = get_parser_request(CookieParser::new().duplicates(LastWins), header.as_slice());
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.get("session"), Some(&"second".to_string()));
+
+        let cookie_request = get_parser_request(CookieParser::new().duplicates(RejectDuplicates), header.as_slice());
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.get("session"), None);
+        assert_eq!(cookie.get("other"), Some(&"thing".to_string()));
+        assert_eq!(cookie.get_all("session").len(), 2);
+    }
+
+    #[test]
+    fn check_duplicate_json() {
+        // {"foo":"bar"} followed by {"foo":"baz"}
+        let cookie_request = get_parser_request(CookieParser::new().duplicates(FirstWins),
+                                "thing=j%3A%7B%22foo%22%3A%22bar%22%7D; thing=j%3A%7B%22foo%22%3A%22baz%22%7D");
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        let mut child_map = TreeMap::new();
+        child_map.insert("foo".to_string(), String("bar".to_string()));
+        let mut root_map = TreeMap::new();
+        root_map.insert("thing".to_string(), Object(child_map));
+        assert_eq!(cookie.json, Object(root_map));
+    }
+
+    #[test]
+    fn check_header_samples() {
+        // (Cookie header, expected pairs in order)
+        let samples: &[(&str, &[(&str, &str)])] = &[
+            ("", &[]),
+            ("thing=thing", &[("thing", "thing")]),
+            ("a=b; c=d", &[("a", "b"), ("c", "d")]),
+            ("a=b;c=d", &[("a", "b"), ("c", "d")]),
+            ("a=b;", &[("a", "b")]),
+            ("  a = b \t;\tc=d  ", &[("a", "b"), ("c", "d")]),
+            // Empty and missing values
+            ("a=", &[("a", "")]),
+            ("a=\"\"", &[("a", "")]),
+            // Pairs without a name, or without a `=`, are ignored
+            ("=b; c=d", &[("c", "d")]),
+            ("novalue; c=d", &[("c", "d")]),
+            // Only the first `=` separates name and value
+            ("a=b=c", &[("a", "b=c")]),
+            // DQUOTE-wrapped values, as emitted by Java servlets
+            ("JSESSIONID=\"1A530637289A03B07199A44E8D531427\"",
+                &[("JSESSIONID", "1A530637289A03B07199A44E8D531427")]),
+            ("a=\" b \" ", &[("a", " b ")]),
+            // Unbalanced quotes are kept
+            ("a=\"b", &[("a", "\"b")]),
+            ("a=\"", &[("a", "\"")]),
+            // RFC 2965 clients
+            ("$Version=1; Customer=\"WILE_E_COYOTE\"; $Path=\"/acme\"",
+                &[("$Version", "1"), ("Customer", "WILE_E_COYOTE"), ("$Path", "/acme")]),
+            ("_ga=GA1.2.1234567890.1234567890; _gid=GA1.2.987654321.1234567890; _gat=1",
+                &[("_ga", "GA1.2.1234567890.1234567890"), ("_gid", "GA1.2.987654321.1234567890"), ("_gat", "1")]),
+            ("PHPSESSID=298zf09hf012fh2; csrftoken=u32t4o3tb3gg43",
+                &[("PHPSESSID", "298zf09hf012fh2"), ("csrftoken", "u32t4o3tb3gg43")]),
+            ("lang=en-US; theme=\"dark\"; tz=America%2FNew_York",
+                &[("lang", "en-US"), ("theme", "dark"), ("tz", "America/New_York")])
+        ];
+
+        for &(header, expected) in samples.iter() {
+            let cookie_request = get_parser_request(CookieParser::new().duplicates(LastWins), header);
+            let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+            let expected: Vec<(String, String)> = expected
+                .iter()
+                .map(|&(key, val)| (key.to_string(), val.to_string()))
+                .collect();
+            assert_eq!(cookie.pairs, expected);
+        }
+    }
+
+    // Parse a given `String` as an HTTP Cookie header, using a strict CookieParser
+    #[test]
+    fn check_strict() {
+        let cookie_request = get_parser_request(CookieParser::new().validation(Strict),
+            "good=thing; bad name=thing; bad[name]=thing; comma=a,b; space=a b; \
+             utf8=%E2%9C%93; latin1=%FF; quoted=\"thing\"");
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        let mut map = HashMap::new();
+        map.insert("good".to_string(), "thing".to_string());
+        map.insert("utf8".to_string(), "\u2713".to_string());
+        map.insert("quoted".to_string(), "thing".to_string());
+        assert_eq!(cookie.map, map);
+        assert_eq!(cookie.rejected, vec![("bad name".to_string(), InvalidName),
+                                         ("bad[name]".to_string(), InvalidName),
+                                         ("comma".to_string(), InvalidValue),
+                                         ("space".to_string(), InvalidValue),
+                                         ("latin1".to_string(), InvalidEncoding)]);
+    }
+
+    #[test]
+    fn check_lenient() {
+        // Lenient parsing accepts anything, replacing invalid UTF-8
+        let cookie_request = get_cookie_request(None, "comma=a,b; latin1=%FF".to_string());
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        let mut map = HashMap::new();
+        map.insert("comma".to_string(), "a,b".to_string());
+        map.insert("latin1".to_string(), "\ufffd".to_string());
+        assert_eq!(cookie.map, map);
+        assert!(cookie.rejected.is_empty());
+    }
+
+    // Set a cookie with `SetCookie`, and parse it back with `CookieParser`
+    fn roundtrip(parser: CookieParser, value: &str) -> Option<String> {
+        roundtrip_named(parser, "thing", value)
+    }
+
+    // Set a cookie through the parsed `Cookie`, and parse its Set-Cookie header back
+    fn set_and_parse(parser: CookieParser, set: |&mut Response, &Cookie|) -> Request {
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        let mut res = response::new();
+        let mut parser = parser;
+        parser.enter(&mut req, &mut res);
+        set(&mut res, req.alloy.find::<Cookie>().unwrap());
+        let header = res.headers.extensions.find(&"Set-Cookie".to_string()).unwrap().clone();
+        // Browsers send back only the pair, before any attributes
+        let pair = header.as_slice().split(';').next().unwrap().to_string();
+
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        req.headers.extensions.insert("Cookie".to_string(), pair);
+        parser.enter(&mut req, &mut response::new());
+        req
+    }
+
+    fn roundtrip_named(parser: CookieParser, name: &str, value: &str) -> Option<String> {
+        use response::{SetCookie, HeaderCollection};
+        let req = set_and_parse(parser, |res, signer| {
+            res.set_cookie(signer, (name.to_string(), value.to_string()), HeaderCollection::secured()).unwrap();
+        });
+        req.alloy.find::<Cookie>().unwrap().get(name).map(|val| val.clone())
+    }
+
+    fn roundtrip_json(parser: CookieParser, name: &str, value: Json) -> Option<Json> {
+        use response::{SetCookie, HeaderCollection};
+        let req = set_and_parse(parser, |res, signer| {
+            res.set_json_cookie(signer, (name.to_string(), value.clone()), HeaderCollection::secured()).unwrap();
+        });
+        req.alloy.find::<Cookie>().unwrap().json.find(&name.to_string()).map(|json| json.clone())
+    }
+
+    // Every way of signing and encoding cookies, which should all read back what they set
+    fn roundtrip_parsers() -> Vec<CookieParser> {
+        use mac::HmacSha512;
+        let signed = CookieParser::signed_unchecked("@zzmp".to_string());
+        vec![
+            CookieParser::new(),
+            CookieParser::new().encoding(Base64Url),
+            signed.clone(),
+            signed.clone().encoding(Base64Url),
+            signed.clone().signature_format(ExpressSignature),
+            signed.signer(HmacSha512)
+        ]
+    }
+
+    // Prefixed names are refused without their attributes
+    fn is_settable(name: &str) -> bool {
+        !name.is_empty() && !name.starts_with("__Secure-") && !name.starts_with("__Host-")
+    }
+
+    #[test]
+    fn check_encoding_roundtrip() {
+        for &value in ["a b", "a+b", "a%20b", "\u2713", ""].iter() {
+            assert_eq!(roundtrip(CookieParser::new(), value), Some(value.to_string()));
+            assert_eq!(roundtrip(CookieParser::new().encoding(Base64Url), value), Some(value.to_string()));
+            assert_eq!(roundtrip(CookieParser::signed_unchecked("@zzmp".to_string()), value), Some(value.to_string()));
+            assert_eq!(roundtrip(CookieParser::signed_unchecked("@zzmp".to_string()).encoding(Base64Url), value),
+                       Some(value.to_string()));
+        }
+    }
+
+    #[test]
+    fn check_encoding_property() {
+        fn prop(value: String) -> bool {
+            roundtrip(CookieParser::new(), value.as_slice()) == Some(value.clone()) &&
+            roundtrip(CookieParser::signed_unchecked("@zzmp".to_string()).encoding(Base64Url), value.as_slice()) == Some(value)
+        }
+        quickcheck(prop as fn(String) -> bool);
+    }
+
+    #[test]
+    fn check_roundtrip_samples() {
+        let samples = ["", " ", " a b ", "a+b", "a%20b", "%", "\"quoted\"", ";=,\\", "a.b.c",
+                       "s:a.b", "s%3Aa.b", "s:", "j", "j:", "j:a", "j:{}", "%6A%3Aa", "\t\r\n\x00", "\u2713", "caf\u00e9"];
+        for parser in roundtrip_parsers().into_iter() {
+            for &name in samples.iter() {
+                for &value in samples.iter() {
+                    if !is_settable(name) { continue }
+                    assert_eq!(roundtrip_named(parser.clone(), name, value), Some(value.to_string()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_roundtrip_property() {
+        fn prop(name: String, value: String) -> TestResult {
+            if !is_settable(name.as_slice()) { return TestResult::discard() }
+            TestResult::from_bool(roundtrip_parsers().into_iter().all(|parser| {
+                roundtrip_named(parser, name.as_slice(), value.as_slice()) == Some(value.clone())
+            }))
+        }
+        quickcheck(prop as fn(String, String) -> TestResult);
+    }
+
+    #[test]
+    fn check_json_roundtrip_property() {
+        fn prop(name: String, key: String, text: String, number: i32, flag: bool) -> TestResult {
+            if !is_settable(name.as_slice()) { return TestResult::discard() }
+            let mut child_map = TreeMap::new();
+            child_map.insert(key, json::List(vec![String(text), json::Number(number as f64), json::Boolean(flag), Null]));
+            let value = Object(child_map);
+            TestResult::from_bool(roundtrip_parsers().into_iter().all(|parser| {
+                roundtrip_json(parser, name.as_slice(), value.clone()) == Some(value.clone())
+            }))
+        }
+        quickcheck(prop as fn(String, String, String, i32, bool) -> TestResult);
+    }
+
+    #[test]
+    fn check_raw_encoding() {
+        // Raw values are passed through, for cookies set by other systems
+        let req = get_parser_request(CookieParser::new().encoding(Raw), "other=a+b%20c");
+        let cookie = req.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.get("other"), Some(&"a+b%20c".to_string()));
+    }
+
+    #[test]
+    fn check_invalid_base64() {
+        let req = get_parser_request(CookieParser::new().encoding(Base64Url), "thing=a!b; other=YSBi");
+        let cookie = req.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.get("other"), Some(&"a b".to_string()));
+        assert_eq!(cookie.rejected, vec![("thing".to_string(), InvalidEncoding)]);
+    }
+
+    // Parse a given `String` as an HTTP Cookie header, with Express-formatted signatures
+    #[test]
+    fn check_express_signature() {
+        // Test vectors produced by Express' `cookie-signature` and `cookie-parser`:
+        // res.cookie(name, value, { signed: true })
+        let cookie_request = get_parser_request(CookieParser::signed_unchecked("tobiiscool".to_string()).signature_format(ExpressSignature),
+                                "thing=s%3Ahello.DGDUkGlIkCzPz%2BC0B064FNgHdEjox7ch8tOBGslZ5QI");
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.get("thing"), Some(&"hello".to_string()));
+
+        let cookie_request = get_parser_request(CookieParser::signed_unchecked("keyboard cat".to_string()).signature_format(ExpressSignature),
+                                "thing=s%3Afoobarbaz.CP7AWaXDfAKIRfH49dQzKJx7sKzzSoPq7%2FAcBBRVwlI");
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.get("thing"), Some(&"foobarbaz".to_string()));
+
+        // Unescaped signatures are read as well
+        let cookie_request = get_parser_request(CookieParser::signed_unchecked("keyboard cat".to_string()).signature_format(ExpressSignature),
+                                "thing=s:foobarbaz.CP7AWaXDfAKIRfH49dQzKJx7sKzzSoPq7/AcBBRVwlI");
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.get("thing"), Some(&"foobarbaz".to_string()));
+
+        // The hex signature of the same value is not accepted
+        let cookie_request = get_parser_request(CookieParser::signed_unchecked("@zzmp".to_string()).signature_format(ExpressSignature),
+                                "thing=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb");
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.get("thing"), None);
+    }
+
+    #[test]
+    fn check_express_json() {
+        // res.cookie('thing', { foo: 'bar' }, { signed: true }) with secret "keyboard cat"
+        let cookie_request = get_parser_request(CookieParser::signed_unchecked("keyboard cat".to_string()).signature_format(ExpressSignature),
+                                "thing=s%3Aj%3A%7B%22foo%22%3A%22bar%22%7D.AfqA4htnrNIn1SH1JkfWPhJ9D2f%2Fg60c%2Bz%2BrJkSS6d4");
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        let mut child_map = TreeMap::new();
+        child_map.insert("foo".to_string(), String("bar".to_string()));
+        let mut root_map = TreeMap::new();
+        root_map.insert("thing".to_string(), Object(child_map));
+        assert_eq!(cookie.json, Object(root_map));
+    }
+
+    #[test]
+    fn check_express_roundtrip() {
+        let parser = CookieParser::signed_unchecked("tobiiscool".to_string()).signature_format(ExpressSignature);
+        assert_eq!(roundtrip(parser, "hello"), Some("hello".to_string()));
+    }
+
+    // Parse a given `String` as an HTTP Cookie header, with a signed `parser`
+    #[test]
+    fn check_signer() {
+        use mac::{HmacSha256, HmacSha512};
+        // The HMAC-SHA512 and HMAC-SHA256 hashes of key "@zzmp" and message "thung"
+        let sha512 = "thing=s:thung.sha512$a393a6a5c3886ba00fdcb1ae354d770c77612b351d2115780b9463503daed220\
+                      b6c9e4fd7187cc47b8cb78f6e2c488ba7a3c5b0b6da47a70287cfdb3d93317c4";
+        let sha256 = "thing=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb";
+        let parser = CookieParser::signed_unchecked("@zzmp".to_string()).signer(HmacSha512);
+
+        let cookie_request = get_parser_request(parser.clone(), sha512);
+        assert_eq!(cookie_request.alloy.find::<Cookie>().unwrap().get("thing"), Some(&"thung".to_string()));
+        // Untagged signatures are SHA-256, which is no longer accepted
+        let cookie_request = get_parser_request(parser.clone(), sha256);
+        assert_eq!(cookie_request.alloy.find::<Cookie>().unwrap().get("thing"), None);
+        // Unless it is still accepted, during a transition
+        let cookie_request = get_parser_request(parser.clone().accept(HmacSha256), sha256);
+        assert_eq!(cookie_request.alloy.find::<Cookie>().unwrap().get("thing"), Some(&"thung".to_string()));
+        // The tag must match the signature
+        let cookie_request = get_parser_request(parser.accept(HmacSha256),
+            "thing=s:thung.sha512$e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb");
+        assert_eq!(cookie_request.alloy.find::<Cookie>().unwrap().get("thing"), None);
+    }
+
+    #[test]
+    fn check_signer_roundtrip() {
+        use mac::{HmacSha384, Blake2bMac};
+        let parser = CookieParser::signed_unchecked("@zzmp".to_string());
+        assert_eq!(roundtrip(parser.clone().signer(HmacSha384), "a b"), Some("a b".to_string()));
+        assert_eq!(roundtrip(parser.clone().signer(Blake2bMac), "a b"), Some("a b".to_string()));
+        assert_eq!(roundtrip(parser.signer(Blake2bMac).signature_format(ExpressSignature), "a b"),
+                   Some("a b".to_string()));
+    }
+
+    #[test]
+    fn check_derived() {
+        use keys::{MasterKey, Pbkdf2};
+        use mac::HmacSha256;
+        // Signed with the key derived by HKDF SHA-256 for `thing`
+        let signed = "s:thung.8024b715103df7295c72776b144015f86ac144ee65a19295b17327341f57d461";
+        let master = MasterKey::new("f3a1c94e0b7d2e68a5c0d9b4e1f7a23c").unwrap();
+        let parser = CookieParser::derived(master.clone());
+
+        let cookie_request = get_parser_request(parser.clone(), format!("thing={}", signed).as_slice());
+        assert_eq!(cookie_request.alloy.find::<Cookie>().unwrap().get("thing"), Some(&"thung".to_string()));
+        // The signature is not valid for any other cookie name
+        let cookie_request = get_parser_request(parser.clone(), format!("other={}", signed).as_slice());
+        assert_eq!(cookie_request.alloy.find::<Cookie>().unwrap().get("other"), None);
+        // Nor is a signature made with the master secret itself
+        let cookie_request = get_parser_request(parser.clone(),
+            "thing=s:thung.87e3771f2fbddd27de637fd39df6e0f9c4aa835751652b4eb051145b505b7c6b");
+        assert_eq!(cookie_request.alloy.find::<Cookie>().unwrap().get("thing"), None);
+        // Unsigned cookies are not parsed
+        let cookie_request = get_parser_request(parser.clone(), "thing=thung");
+        assert_eq!(cookie_request.alloy.find::<Cookie>().unwrap().get("thing"), None);
+
+        assert_eq!(roundtrip(parser, "a b"), Some("a b".to_string()));
+        let parser = CookieParser::derived(master.kdf(Pbkdf2(HmacSha256, 10)));
+        assert_eq!(roundtrip(parser, "a b"), Some("a b".to_string()));
+    }
+
+    #[test]
+    fn check_outcomes() {
+        let cookie_request = get_cookie_request(Some("@zzmp".to_string()),
+            "good=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; \
+             tampered=s:thing.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; \
+             plain=thung; broken=s:thung".to_string());
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.outcomes, vec![("good".to_string(), Verified),
+                                         ("tampered".to_string(), InvalidSignature),
+                                         ("plain".to_string(), Unsigned),
+                                         ("broken".to_string(), Malformed)]);
+        assert_eq!(cookie.verification("good"), Some(Verified));
+        assert_eq!(cookie.verification("tampered"), Some(InvalidSignature));
+        assert_eq!(cookie.verification("missing"), None);
+
+        // Unsigned cookies are kept without a secret, but signed cookies cannot be verified
+        let cookie_request = get_cookie_request(None, "plain=thung; signed=s:thung.abc".to_string());
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.verification("plain"), Some(Unsigned));
+        assert_eq!(cookie.verification("signed"), Some(Unverifiable));
+        assert_eq!(cookie.get("signed"), None);
+    }
+
+    #[test]
+    fn check_duplicate_outcomes() {
+        // A tampered duplicate does not hide a verified cookie
+        let cookie_request = get_cookie_request(Some("@zzmp".to_string()),
+            "thing=s:thing.0000; thing=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb".to_string());
+        let cookie = cookie_request.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.verification("thing"), Some(Verified));
+        assert_eq!(cookie.outcomes.len(), 2);
+    }
+
+    // Records the name of each cookie reported as unverified
+    struct Alerts {
+        names: Mutex<Vec<String>>
+    }
+
+    impl CookieObserver for Alerts {
+        fn unverified(&self, _req: &Request, name: &str, _outcome: &Verification) {
+            self.names.lock().push(name.to_string());
+        }
+    }
+
+    #[test]
+    fn check_observers() {
+        let metrics = Arc::new(CookieMetrics::new());
+        let alerts = Arc::new(Alerts { names: Mutex::new(Vec::new()) });
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        req.headers.extensions.insert("Cookie".to_string(), format!(
+            "good=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; \
+             tampered=s:thing.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; \
+             plain=thung; broken=s:thung; big={}", "x".repeat(100)));
+        CookieParser::signed_unchecked("@zzmp".to_string())
+            .max_size(100)
+            .observer(metrics.clone())
+            .observer(alerts.clone())
+            .enter(&mut req, &mut response::new());
+
+        let cookie = req.alloy.find::<Cookie>().unwrap();
+        assert_eq!(cookie.rejected, vec![("big".to_string(), Oversized)]);
+        assert_eq!(metrics.counts(), CookieCounts {
+            accepted: 1,
+            rejected: 0,
+            oversized: 1,
+            invalid_signature: 1,
+            unsigned: 1,
+            malformed: 1,
+            expired: 0
+        });
+        assert_eq!(*alerts.names.lock(), vec!["tampered".to_string(), "plain".to_string(), "broken".to_string()]);
+    }
+
+    #[test]
+    fn check_observers_without_secret() {
+        // Well-formed signed cookies cannot be verified without a secret, which is not tampering
+        let metrics = Arc::new(CookieMetrics::new());
+        let alerts = Arc::new(Alerts { names: Mutex::new(Vec::new()) });
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        req.headers.extensions.insert("Cookie".to_string(),
+            "good=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; plain=thung".to_string());
+        CookieParser::new()
+            .observer(metrics.clone())
+            .observer(alerts.clone())
+            .enter(&mut req, &mut response::new());
+
+        assert_eq!(*alerts.names.lock(), vec![]);
+        assert_eq!(metrics.counts(), CookieCounts {
+            accepted: 1,
+            rejected: 0,
+            oversized: 0,
+            invalid_signature: 0,
+            unsigned: 0,
+            malformed: 0,
+            expired: 0
+        });
+    }
+
+    #[test]
+    fn check_codec() {
+        use rails::RailsCodec;
+        // cookies.signed[:foo] = "bar" and cookies.signed[:prefs] = { theme: "dark" },
+        // as set by Rails 6 with secret_key_base "iron cookie test secret key base"
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        req.headers.extensions.insert("Cookie".to_string(),
+            "foo=eyJfcmFpbHMiOnsibWVzc2FnZSI6IkltSmhjaUk9IiwiZXhwIjpudWxsLCJwdXIiOiJjb29raWUuZm9vIn19\
+             --89a51cb4ee42af0bb265e96858fea0b1fb69ecf9; \
+             prefs=eyJfcmFpbHMiOnsibWVzc2FnZSI6ImV5SjBhR1Z0WlNJNkltUmhjbXNpZlE9PSIsImV4cCI6bnVsbCwicHVyIjoiY29va2llLnByZWZzIn19\
+             --46407bb996063039d8f2637a6e5fbcb9f6a8cd9b; \
+             unsigned=thing".to_string());
+        CookieParser::new()
+            .codec(RailsCodec::signed("iron cookie test secret key base"))
+            .enter(&mut req, &mut response::new());
+        let cookie = req.alloy.find::<Cookie>().unwrap();
+
+        let mut map = HashMap::new();
+        map.insert("foo".to_string(), "bar".to_string());
+        assert_eq!(cookie.map, map);
+
+        let mut child_map = TreeMap::new();
+        child_map.insert("theme".to_string(), String("dark".to_string()));
+        let mut root_map = TreeMap::new();
+        root_map.insert("prefs".to_string(), Object(child_map));
+        assert_eq!(cookie.json, Object(root_map));
+    }
+
+    #[test]
+    fn check_codec_roundtrip() {
+        use rails::RailsCodec;
+        let parser = CookieParser::new().codec(RailsCodec::encrypted("iron cookie test secret key base").unwrap());
+        assert_eq!(roundtrip(parser, "a b+c"), Some("a b+c".to_string()));
+    }
 }
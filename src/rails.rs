@@ -0,0 +1,289 @@
+//! Rails compatibility - share signed and encrypted cookies with Rails
+
+use std::io::IoResult;
+use std::rand::{OsRng, Rng};
+use std::sync::{Arc, Mutex};
+use serialize::json;
+use serialize::hex::ToHex;
+use serialize::base64::{ToBase64, FromBase64, STANDARD};
+use crypto::aes::KeySize256;
+use crypto::aes_gcm::AesGcm;
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::util::fixed_time_eq;
+use time;
+use super::{ValueCodec, Percent, HmacDigest, HmacSha1};
+use super::{Verification, InvalidSignature, Malformed, Expired};
+use super::codec::{to_json_message, from_json_message};
+
+// The salts and iteration count of `ActiveSupport::KeyGenerator`, as used by `ActionDispatch::Cookies`
+static SIGNED_COOKIE_SALT: &'static str = "signed cookie";
+static ENCRYPTED_COOKIE_SALT: &'static str = "authenticated encrypted cookie";
+static ITERATIONS: u32 = 1000;
+
+/// A `ValueCodec` for cookies set by Rails, through `cookies.signed` or `cookies.encrypted`.
+///
+/// Keys are derived from the application's `secret_key_base`, exactly as Rails derives them.
+/// Rails 5.2 and later are supported, with the `:json` cookie serializer.
+/// Purpose and expiry metadata is verified when present, and written when setting cookies.
+///
+/// JSON strings are stored in `Cookie.map`; any other JSON is stored in `Cookie.json`.
+#[deriving(Clone)]
+pub struct RailsCodec {
+    key: Vec<u8>,
+    digest: HmacDigest,
+    // The source of IVs, for encrypted cookies only
+    rng: Option<Arc<Mutex<OsRng>>>
+}
+
+impl RailsCodec {
+    /// Read and write cookies of `cookies.signed`, verified with HMAC SHA1.
+    ///
+    /// SHA1 is the default digest of Rails 5.2 and 6.
+    pub fn signed(secret_key_base: &str) -> RailsCodec {
+        RailsCodec::signed_with_digest(secret_key_base, HmacSha1)
+    }
+
+    /// Read and write cookies of `cookies.signed`, with the given digest.
+    ///
+    /// The digest is used both to derive keys, as configured by
+    /// `key_generator_hash_digest_class`, and to sign, as configured by `signed_cookie_digest`.
+    pub fn signed_with_digest(secret_key_base: &str, digest: HmacDigest) -> RailsCodec {
+        RailsCodec {
+            key: digest.pbkdf2(secret_key_base.as_bytes(), SIGNED_COOKIE_SALT.as_bytes(), ITERATIONS, 64),
+            digest: digest,
+            rng: None
+        }
+    }
+
+    /// Read and write cookies of `cookies.encrypted`, encrypted with AES-256-GCM.
+    ///
+    /// This is also the format of the Rails session cookie.
+    ///
+    /// Fails if the operating system's random number generator, used for IVs, cannot be opened.
+    pub fn encrypted(secret_key_base: &str) -> IoResult<RailsCodec> {
+        RailsCodec::encrypted_with_digest(secret_key_base, HmacSha1)
+    }
+
+    /// Read and write cookies of `cookies.encrypted`, deriving keys with the given digest.
+    pub fn encrypted_with_digest(secret_key_base: &str, digest: HmacDigest) -> IoResult<RailsCodec> {
+        let rng = try!(OsRng::new());
+        Ok(RailsCodec {
+            key: digest.pbkdf2(secret_key_base.as_bytes(), ENCRYPTED_COOKIE_SALT.as_bytes(), ITERATIONS, 32),
+            digest: digest,
+            rng: Some(Arc::new(Mutex::new(rng)))
+        })
+    }
+
+    // `ActiveSupport::MessageVerifier#generate`
+    fn generate(&self, message: &str) -> String {
+        let data = message.as_bytes().to_base64(STANDARD);
+        let digest = self.digest.hmac(self.key.as_slice(), data.as_bytes()).as_slice().to_hex();
+        data.append("--").append(digest.as_slice())
+    }
+
+    // `ActiveSupport::MessageVerifier#verified`
+    fn verified(&self, value: &str) -> Result<Vec<u8>, Verification> {
+        let parts: Vec<&str> = value.split_str("--").collect();
+        if parts.len() != 2 { return Err(Malformed) }
+        let expected = self.digest.hmac(self.key.as_slice(), parts[0].as_bytes()).as_slice().to_hex();
+        if !fixed_time_eq(parts[1].as_bytes(), expected.as_bytes()) { return Err(InvalidSignature) }
+        parts[0].from_base64().map_err(|_| Malformed)
+    }
+
+    // `ActiveSupport::MessageEncryptor#encrypt_and_sign`, with an AES-256-GCM cipher
+    fn encrypt_and_sign(&self, rng: &Mutex<OsRng>, message: &str) -> String {
+        let mut iv = Vec::from_elem(12, 0u8);
+        rng.lock().fill_bytes(iv.as_mut_slice());
+
+        let mut cipher = AesGcm::new(KeySize256, self.key.as_slice(), iv.as_slice(), &[]);
+        let mut encrypted = Vec::from_elem(message.len(), 0u8);
+        let mut tag = Vec::from_elem(16, 0u8);
+        cipher.encrypt(message.as_bytes(), encrypted.as_mut_slice(), tag.as_mut_slice());
+
+        encrypted.as_slice().to_base64(STANDARD)
+            .append("--").append(iv.as_slice().to_base64(STANDARD).as_slice())
+            .append("--").append(tag.as_slice().to_base64(STANDARD).as_slice())
+    }
+
+    // `ActiveSupport::MessageEncryptor#decrypt_and_verify`, with an AES-256-GCM cipher
+    fn decrypt_and_verify(&self, value: &str) -> Result<Vec<u8>, Verification> {
+        let parts: Vec<Vec<u8>> = value
+            .split_str("--")
+            .filter_map(|part| part.from_base64().ok())
+            .collect();
+        if parts.len() != 3 || parts[1].len() != 12 || parts[2].len() != 16 { return Err(Malformed) }
+
+        let mut cipher = AesGcm::new(KeySize256, self.key.as_slice(), parts[1].as_slice(), &[]);
+        let mut decrypted = Vec::from_elem(parts[0].len(), 0u8);
+        if cipher.decrypt(parts[0].as_slice(), decrypted.as_mut_slice(), parts[2].as_slice()) {
+            Ok(decrypted)
+        } else {
+            Err(InvalidSignature)
+        }
+    }
+}
+
+impl ValueCodec for RailsCodec {
+    fn encode(&self, name: &str, value: &str) -> String {
+        // The `:json` cookie serializer
+        let message = wrap(name, to_json_message(value).as_slice());
+        let encoded = match self.rng {
+            Some(ref rng) => self.encrypt_and_sign(&**rng, message.as_slice()),
+            None          => self.generate(message.as_slice())
+        };
+        // Rack escapes cookie values as form components
+        Percent.encode(encoded.as_slice())
+    }
+
+    fn decode(&self, name: &str, value: &str) -> Option<String> {
+        self.verify(name, value).ok()
+    }
+
+    fn verify(&self, name: &str, value: &str) -> Result<String, Verification> {
+        let value = try!(Percent.decode(value)
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(Malformed));
+        let message = try!(if self.rng.is_some() {
+            self.decrypt_and_verify(value.as_slice())
+        } else {
+            self.verified(value.as_slice())
+        });
+        let message = try!(String::from_utf8(message).map_err(|_| Malformed));
+        from_json_message(try!(unwrap(name, message))).ok_or(Malformed)
+    }
+}
+
+// Wrap a message in `ActiveSupport::Messages::Metadata`, with the purpose of the cookie
+fn wrap(name: &str, message: &str) -> String {
+    format!("{{\"_rails\":{{\"message\":{},\"exp\":null,\"pur\":{}}}}}",
+            json::String(message.as_bytes().to_base64(STANDARD)),
+            json::String(purpose(name)))
+}
+
+// Unwrap and verify any `ActiveSupport::Messages::Metadata` of a message
+fn unwrap(name: &str, message: String) -> Result<String, Verification> {
+    let metadata = match json::from_str(message.as_slice()) {
+        Ok(json::Object(mut root)) => match root.pop(&"_rails".to_string()) {
+            Some(json::Object(metadata)) => metadata,
+            // Messages set before Rails 6 carry no metadata
+            _                            => return Ok(message)
+        },
+        _                          => return Ok(message)
+    };
+
+    // Messages signed for another cookie are not valid for this one
+    match metadata.find(&"pur".to_string()) {
+        Some(&json::String(ref pur)) if *pur != purpose(name) => return Err(InvalidSignature),
+        _                                                     => ()
+    }
+    match metadata.find(&"exp".to_string()) {
+        Some(&json::String(ref exp)) if expired(exp.as_slice()) => return Err(Expired),
+        _                                                       => ()
+    }
+    match metadata.find(&"message".to_string()) {
+        Some(&json::String(ref message)) => {
+            message.as_slice().from_base64().ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or(Malformed)
+        },
+        _                                => Err(Malformed)
+    }
+}
+
+fn purpose(name: &str) -> String {
+    "cookie.".to_string().append(name)
+}
+
+// Expiry is written in ISO 8601, with milliseconds: `2000-01-01T00:00:00.000Z`
+fn expired(exp: &str) -> bool {
+    if exp.len() < 19 || !exp.is_char_boundary(19) { return true }
+    match time::strptime(exp.slice_to(19), "%Y-%m-%dT%H:%M:%S") {
+        Ok(tm) => tm.to_timespec().sec <= time::get_time().sec,
+        Err(_) => true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::codec::ValueCodec;
+    use super::super::parser::{Expired, InvalidSignature, Malformed};
+
+    // Test vectors follow `ActionDispatch::Cookies` of Rails 6, with this `secret_key_base`
+    static SECRET_KEY_BASE: &'static str = "iron cookie test secret key base";
+
+    #[test]
+    fn check_signed() {
+        let codec = RailsCodec::signed(SECRET_KEY_BASE);
+        // cookies.signed[:foo] = "bar"
+        let value = "eyJfcmFpbHMiOnsibWVzc2FnZSI6IkltSmhjaUk9IiwiZXhwIjpudWxsLCJwdXIiOiJjb29raWUuZm9vIn19\
+                     --89a51cb4ee42af0bb265e96858fea0b1fb69ecf9";
+        assert_eq!(codec.decode("foo", value), Some("bar".to_string()));
+        assert_eq!(codec.encode("foo", "bar"), value.to_string());
+        // The purpose binds the value to its cookie name
+        assert_eq!(codec.decode("other", value), None);
+        assert_eq!(codec.verify("other", value), Err(InvalidSignature));
+        assert_eq!(codec.verify("foo", "bar"), Err(Malformed));
+    }
+
+    #[test]
+    fn check_signed_legacy() {
+        // cookies.signed[:foo] = "bar", without metadata
+        let codec = RailsCodec::signed(SECRET_KEY_BASE);
+        assert_eq!(codec.decode("foo", "ImJhciI%3D--c40e200db0ffdbaea1cb52581070946b5242f6f4"),
+                   Some("bar".to_string()));
+        assert_eq!(codec.decode("foo", "ImJhciI%3D--c40e200db0ffdbaea1cb52581070946b5242f6f5"), None);
+    }
+
+    #[test]
+    fn check_signed_expired() {
+        // cookies.signed[:foo] = { value: "bar", expires: Time.utc(2000) }
+        let codec = RailsCodec::signed(SECRET_KEY_BASE);
+        assert_eq!(codec.decode("foo", "eyJfcmFpbHMiOnsibWVzc2FnZSI6IkltSmhjaUk9IiwiZXhwIjoiMjAwMC0wMS0wMVQwMDowMDowMC4wMDBaIiwicHVyIjoiY29va2llLmZvbyJ9fQ%3D%3D\
+                                        --a44bec1d50af2f4dc8c266dacf569d30228dc86c"), None);
+        assert_eq!(codec.verify("foo", "eyJfcmFpbHMiOnsibWVzc2FnZSI6IkltSmhjaUk9IiwiZXhwIjoiMjAwMC0wMS0wMVQwMDowMDowMC4wMDBaIiwicHVyIjoiY29va2llLmZvbyJ9fQ%3D%3D\
+                                        --a44bec1d50af2f4dc8c266dacf569d30228dc86c"), Err(Expired));
+    }
+
+    #[test]
+    fn check_expiry() {
+        assert!(super::expired("2000-01-01T00:00:00.000Z"));
+        assert!(!super::expired("9999-01-01T00:00:00.000Z"));
+        // Found by fuzzing: malformed expiry is expired, even across a multibyte character
+        assert!(super::expired("2000-01-01T00:00:0€.000Z"));
+        assert!(super::expired("2000"));
+    }
+
+    #[test]
+    fn check_signed_json() {
+        // cookies.signed[:prefs] = { theme: "dark" }
+        let codec = RailsCodec::signed(SECRET_KEY_BASE);
+        assert_eq!(codec.decode("prefs", "eyJfcmFpbHMiOnsibWVzc2FnZSI6ImV5SjBhR1Z0WlNJNkltUmhjbXNpZlE9PSIsImV4cCI6bnVsbCwicHVyIjoiY29va2llLnByZWZzIn19\
+                                          --46407bb996063039d8f2637a6e5fbcb9f6a8cd9b"),
+                   Some("j:{\"theme\":\"dark\"}".to_string()));
+    }
+
+    #[test]
+    fn check_encrypted() {
+        let codec = RailsCodec::encrypted(SECRET_KEY_BASE).unwrap();
+        // session[:user_id] = 42
+        assert_eq!(codec.decode("session", "nkFR1c9QpbAaqGzkFLlBABm8UMneZY03oDFQQnQXmVOHfFjPKDCcMx50Nn01D0OmDSBdfPZyBf60CLtFoavkhra2XAFBGmQ5P8XLn8cpkQ%3D%3D\
+                                            --AAECAwQFBgcICQoL--13loWOLQfp1GfnCFskFIRg%3D%3D"),
+                   Some("j:{\"user_id\":42}".to_string()));
+        // cookies.encrypted[:token] = "abc"
+        let value = "nkFR1c9QpbAaqGzkFLlBABm8UMneZaEjrGlqCWtT403JcmnVZTq%2FKEIlOHNnH0n0FThQZvV1QLnqCaYM%2FuellKQ%3D\
+                     --AAECAwQFBgcICQoL--F0ZypkuxUGKwNXLzIIRHvA%3D%3D";
+        assert_eq!(codec.decode("token", value), Some("abc".to_string()));
+        // Signed cookies cannot be read as encrypted cookies
+        assert_eq!(RailsCodec::signed(SECRET_KEY_BASE).decode("token", value), None);
+    }
+
+    #[test]
+    fn check_encrypted_roundtrip() {
+        let codec = RailsCodec::encrypted(SECRET_KEY_BASE).unwrap();
+        let value = codec.encode("token", "a b+c");
+        assert_eq!(codec.decode("token", value.as_slice()), Some("a b+c".to_string()));
+        let value = codec.encode("token", "j:{\"id\":1}");
+        assert_eq!(codec.decode("token", value.as_slice()), Some("j:{\"id\":1}".to_string()));
+    }
+}
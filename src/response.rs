@@ -1,9 +1,9 @@
 //! Setting functionality - set cookie data
 
-use url::{utf8_percent_encode, FORM_URLENCODED_ENCODE_SET};
 use serialize::json::{Json, Number, String, Boolean, List, Object, Null};
 use iron::Response;
-use super::Cookie;
+use super::{Cookie, Encoding, Raw};
+use parser::ESCAPED_JSON;
 use time::Tm;
 use std::collections::TreeMap;
 
@@ -18,59 +18,91 @@ pub trait SetCookie {
     /// Only one cookie may sent per response, with the given key/value.
     /// Doing otherwise will result in ***undefined behavior***.
     ///
-    /// Keys/values may contain restricted characters, but they will be URI encoded in the cookie.
+    /// Keys/values may contain restricted characters, but they will be encoded in the cookie,
+    /// using the `Encoding` of the given `Cookie`.
     ///
     /// They will be decoded when the cookie is returned to the server.
+    /// Values prefixed with `j:` are escaped, so they are not read as JSON,
+    /// unless they are written unchanged by a `ValueCodec` or the `Raw` encoding.
     ///
     /// Cookies ***must*** be set before the response body is sent.
     /// Headers are flushed as soon anything is sent in the response body.
-    fn set_cookie(&mut self, &Cookie, (String, String), HeaderCollection);
+    ///
+    /// Cookies whose names carry a `__Secure-` or `__Host-` prefix will be refused,
+    /// and no header set, unless the given headers satisfy that prefix.
+    /// See `HeaderCollection::check_prefix`.
+    fn set_cookie(&mut self, &Cookie, (String, String), HeaderCollection) -> Result<(), SetCookieError>;
 
     /// Set a cookie as JSON.
     ///
     /// Cookies set as JSON will be available under `cookie.json`.
     /// Otherwise, they behave exactly as normally serialized cookies.
     ///
-    /// Note that restricted characters will still be URI encoded in your cookie.
+    /// Note that restricted characters will still be encoded in your cookie.
     ///
     /// They will be decoded when the cookie is returned to the server.
-    fn set_json_cookie(&mut self, &Cookie, (String, Json), HeaderCollection);
+    fn set_json_cookie(&mut self, &Cookie, (String, Json), HeaderCollection) -> Result<(), SetCookieError>;
+}
+
+/// The reasons a cookie may be refused by `SetCookie`.
+#[deriving(Show, PartialEq, Clone)]
+pub enum SetCookieError {
+    /// A `__Secure-` or `__Host-` prefixed cookie was not marked `Secure`.
+    InsecurePrefix,
+    /// A `__Host-` prefixed cookie specified a `Domain`.
+    HostPrefixDomain,
+    /// A `__Host-` prefixed cookie was not scoped to `Path=/`.
+    HostPrefixPath
 }
 
 impl SetCookie for Response {
     fn set_cookie(&mut self,
                   signer: &Cookie,
-                  (key, value): (String, String),
-                  options: HeaderCollection) {
-
-        self.headers.extensions.insert("Set-Cookie".to_string(),
-            match signer.sign(&value) {
-                Some(signature) => {
-                    utf8_percent_encode(key.as_slice(), FORM_URLENCODED_ENCODE_SET)
-                        .append("=")
-                        .append("s:")
-                        .append(utf8_percent_encode(value.as_slice(), FORM_URLENCODED_ENCODE_SET).as_slice())
-                        .append(".")
-                        .append(signature.as_slice())
-                },
-                None            => {
-                    utf8_percent_encode(key.as_slice(), FORM_URLENCODED_ENCODE_SET)
-                        .append("=")
-                        .append(utf8_percent_encode(value.as_slice(), FORM_URLENCODED_ENCODE_SET).as_slice())
-                }
-            }.append(options.to_cookie_av().as_slice())
-        );
+                  cookie: (String, String),
+                  options: HeaderCollection) -> Result<(), SetCookieError> {
+        set_value(self, signer, cookie, false, options)
     }
 
     fn set_json_cookie(&mut self,
                        signer: &Cookie,
                        (key, value): (String, Json),
-                       options: HeaderCollection) {
+                       options: HeaderCollection) -> Result<(), SetCookieError> {
         let json = "j:".to_string().append(stringify_json(&value).as_slice());
-        self.set_cookie(signer, (key, json), options)
+        set_value(self, signer, (key, json), true, options)
     }
 }
 
+// Set a cookie, escaping the prefix of values beginning `j:` unless they are `json`
+fn set_value(res: &mut Response,
+             signer: &Cookie,
+             (key, value): (String, String),
+             json: bool,
+             options: HeaderCollection) -> Result<(), SetCookieError> {
+    try!(options.check_prefix(key.as_slice()));
+
+    let encoding = &signer.encoding;
+    let encoded = match signer.codec {
+        // Codecs replace our own signatures
+        Some(ref codec) => codec.encode(key.as_slice(), value.as_slice()),
+        None            => match signer.sign(key.as_slice(), &value) {
+            Some(signature) => {
+                "s:".to_string()
+                    .append(encode_value(encoding, value.as_slice(), json).as_slice())
+                    .append(".")
+                    .append(signature.as_slice())
+            },
+            None            => escape_signed(encode_value(encoding, value.as_slice(), json))
+        }
+    };
+    res.headers.extensions.insert("Set-Cookie".to_string(),
+        encoding.encode_name(key.as_slice())
+            .append("=")
+            .append(encoded.as_slice())
+            .append(options.to_cookie_av().as_slice())
+    );
+    Ok(())
+}
+
 fn stringify_json(json: &Json) -> String {
     match *json {
         Object(ref object) => {
@@ -82,7 +114,8 @@ fn stringify_json(json: &Json) -> String {
             "[".to_string().append(ary.connect(",").as_slice()).append("]")
         },
         Number(number) => number.to_string(),
-        String(ref string) => "\"".to_string().append(string.as_slice()).append("\""),
+        // Strings are escaped by the encoder
+        String(_)          => json.to_string(),
         Boolean(true)      => "true".to_string(),
         Boolean(false)     => "false".to_string(),
         Null               => "null".to_string()
@@ -90,7 +123,26 @@ fn stringify_json(json: &Json) -> String {
 }
 
 fn stringify_pair((key, val): (&String, &Json)) -> String {
-    "\"".to_string().append(key.as_slice()).append("\":").append(stringify_json(val).as_slice())
+    stringify_json(&String(key.clone())).append(":").append(stringify_json(val).as_slice())
+}
+
+// Encode a value, escaping the `j:` of plain values which would otherwise be read as JSON.
+// The rest of the value is encoded as though it were the whole value, to be decoded by the parser.
+fn encode_value(encoding: &Encoding, value: &str, json: bool) -> String {
+    if !json && *encoding != Raw && value.starts_with("j:") {
+        ESCAPED_JSON.to_string().append(encoding.encode(value.slice_from(2)).as_slice())
+    } else {
+        encoding.encode(value)
+    }
+}
+
+// Escape the `s` of unsigned values which would otherwise be read as signed, as `s%3A...`
+fn escape_signed(encoded: String) -> String {
+    if encoded.as_slice().starts_with("s%3A") {
+        "%73".to_string().append(encoded.as_slice().slice_from(1))
+    } else {
+        encoded
+    }
 }
 
 /// The headers used to set a cookie.
@@ -137,7 +189,7 @@ impl HeaderCollection {
             .append(head("Domain", self.domain, |v| v).as_slice())
             .append(head("Path", self.path, |v| v).as_slice());
         if self.secure { options.push_str("; Secure"); }
-        if self.http_only { options.push_str("; Http-Only"); }
+        if self.http_only { options.push_str("; HttpOnly"); }
         match self.extensions {
             Some(map) => {
                 for (header, value) in map.iter() {
@@ -148,6 +200,26 @@ impl HeaderCollection {
         }
         options
     }
+
+    /// Check these headers against any prefix on the cookie name `name`.
+    ///
+    /// As described in [RFC 6265bis](https://tools.ietf.org/html/draft-ietf-httpbis-rfc6265bis-02#section-4.1.3),
+    /// cookies named `__Secure-*` must be `Secure`, and cookies named `__Host-*`
+    /// must also be scoped to `Path=/` without a `Domain`.
+    /// Cookies without a prefix are always accepted.
+    pub fn check_prefix(&self, name: &str) -> Result<(), SetCookieError> {
+        if name.starts_with("__Secure-") || name.starts_with("__Host-") {
+            if !self.secure { return Err(InsecurePrefix) }
+        }
+        if name.starts_with("__Host-") {
+            if self.domain.is_some() { return Err(HostPrefixDomain) }
+            match self.path {
+                Some(ref path) if path.as_slice() == "/" => (),
+                _                                         => return Err(HostPrefixPath)
+            }
+        }
+        Ok(())
+    }
 }
 
 impl HeaderCollection {
@@ -191,6 +263,31 @@ impl HeaderCollection {
             extensions: None
         }
     }
+
+    /// Convenience function for a set of cookie headers
+    /// satisfying the `__Secure-` cookie name prefix.
+    ///
+    /// The cookie will be declared `Secure` and `HttpOnly`.
+    pub fn secure_prefixed() -> HeaderCollection {
+        HeaderCollection::secured()
+    }
+
+    /// Convenience function for a set of cookie headers
+    /// satisfying the `__Host-` cookie name prefix.
+    ///
+    /// The cookie will be declared `Secure` and `HttpOnly`,
+    /// scoped to `Path=/`, and bound to the originating host.
+    pub fn host_prefixed() -> HeaderCollection {
+        HeaderCollection {
+            expires: None,
+            max_age: None,
+            domain: None,
+            path: Some("/".to_string()),
+            secure: true,
+            http_only: true,
+            extensions: None
+        }
+    }
 }
 
 fn head<V>(header: &str, value: Option<V>, mutator: |V| -> String) -> String {
@@ -219,24 +316,36 @@ mod test {
     use std::collections::TreeMap;
     use super::*;
     use super::super::cookie::*;
-    use serialize::json::{Json, Object, String};
+    use serialize::json;
+    use serialize::json::{Json, Object, List, String, Number, Boolean, Null};
     use test::mock::response;
+    use quickcheck::quickcheck;
 
     // Set a cookie and return its set value
     fn get_cookie<'a>(headers: HeaderCollection, secret: Option<String>, key: &str, value: &str) -> String {
         let mut res = response::new();
         let signer = Cookie::new(secret);
         let cookie = (key.to_string(), value.to_string());
-        res.set_cookie(&signer, cookie, headers);
+        res.set_cookie(&signer, cookie, headers).unwrap();
         res.headers.extensions.find(&"Set-Cookie".to_string()).unwrap().clone()
     }
 
+    // Attempt to set a cookie, returning the error if it was refused
+    fn set_prefixed_cookie(headers: HeaderCollection, key: &str) -> Result<(), SetCookieError> {
+        let mut res = response::new();
+        let signer = Cookie::new(None);
+        let result = res.set_cookie(&signer, (key.to_string(), "thing".to_string()), headers);
+        // Refused cookies must not be set
+        assert_eq!(result.is_ok(), res.headers.extensions.find(&"Set-Cookie".to_string()).is_some());
+        result
+    }
+
     // Set a JSON cookie and return its set value
     fn get_json_cookie<'a>(headers: HeaderCollection, secret: Option<String>, key: &str, value: Json) -> String {
         let mut res = response::new();
         let signer = Cookie::new(secret);
         let cookie = (key.to_string(), value);
-        res.set_json_cookie(&signer, cookie, headers);
+        res.set_json_cookie(&signer, cookie, headers).unwrap();
         res.headers.extensions.find(&"Set-Cookie".to_string()).unwrap().clone()
     }
 
@@ -246,7 +355,22 @@ mod test {
         let mut obj_map = TreeMap::new();
         obj_map.insert("foo".to_string(), String("bar".to_string()));
         let json = Object(obj_map);
-        assert_eq!("{\"foo\":\"bar\"}".to_string(), super::stringify_json(&json)) // FIXME
+        assert_eq!("{\"foo\":\"bar\"}".to_string(), super::stringify_json(&json)); // FIXME
+        // Strings and keys are escaped
+        let mut obj_map = TreeMap::new();
+        obj_map.insert("a\"b".to_string(), List(vec![String("c\\d\n".to_string())]));
+        assert_eq!("{\"a\\\"b\":[\"c\\\\d\\n\"]}".to_string(), super::stringify_json(&Object(obj_map)));
+    }
+
+    #[test]
+    fn check_stringify_json_property() {
+        fn prop(key: String, text: String, number: i32, flag: bool) -> bool {
+            let mut obj_map = TreeMap::new();
+            obj_map.insert(key, List(vec![String(text), Number(number as f64), Boolean(flag), Null]));
+            let json = Object(obj_map);
+            json::from_str(super::stringify_json(&json).as_slice()).ok() == Some(json)
+        }
+        quickcheck(prop as fn(String, String, i32, bool) -> bool);
     }
 
     #[test]
@@ -279,7 +403,7 @@ mod test {
         headers.extensions.as_mut().unwrap().insert("foo".to_string(), Some("bar".to_string()));
         headers.extensions.as_mut().unwrap().insert("@zzmp".to_string(), None);
         assert_eq!(get_cookie(headers, None, "thing", "thing"),
-            "thing=thing; Max-Age=42; Domain=example.com; Path=/a/path; Secure; Http-Only; @zzmp; foo=bar".to_string());
+            "thing=thing; Max-Age=42; Domain=example.com; Path=/a/path; Secure; HttpOnly; @zzmp; foo=bar".to_string());
     }
 
     #[test]
@@ -300,4 +424,71 @@ mod test {
             // Url component encoded JSON: {"foo":"bar"}
             "thing=j%3A%7B%22foo%22%3A%22bar%22%7D".to_string());
     }
+
+    #[test]
+    fn check_secure_prefix() {
+        assert_eq!(get_cookie(HeaderCollection::secure_prefixed(), None, "__Secure-thing", "thing"),
+            "__Secure-thing=thing; Secure; HttpOnly".to_string());
+        assert_eq!(set_prefixed_cookie(HeaderCollection::empty(), "__Secure-thing"), Err(InsecurePrefix));
+    }
+
+    #[test]
+    fn check_host_prefix() {
+        assert_eq!(get_cookie(HeaderCollection::host_prefixed(), None, "__Host-thing", "thing"),
+            "__Host-thing=thing; Path=/; Secure; HttpOnly".to_string());
+        assert_eq!(set_prefixed_cookie(HeaderCollection::secure_prefixed(), "__Host-thing"), Err(HostPrefixPath));
+
+        let mut headers = HeaderCollection::host_prefixed();
+        headers.domain = Some("example.com".to_string());
+        assert_eq!(set_prefixed_cookie(headers, "__Host-thing"), Err(HostPrefixDomain));
+
+        let mut headers = HeaderCollection::host_prefixed();
+        headers.secure = false;
+        assert_eq!(set_prefixed_cookie(headers, "__Host-thing"), Err(InsecurePrefix));
+    }
+
+    #[test]
+    fn check_unprefixed() {
+        assert_eq!(set_prefixed_cookie(HeaderCollection::empty(), "thing"), Ok(()));
+        // Prefixes are case-sensitive
+        assert_eq!(set_prefixed_cookie(HeaderCollection::empty(), "__host-thing"), Ok(()));
+    }
+
+    #[test]
+    fn check_signed_prefix() {
+        // Unsigned values are escaped so as not to be read as signed
+        let headers = HeaderCollection::empty();
+        assert_eq!(get_cookie(headers, None, "thing", "s:a.b"), "thing=%73%3Aa.b".to_string());
+        let headers = HeaderCollection::empty();
+        assert_eq!(get_cookie(headers, Some("@zzmp".to_string()), "thing", "s:a.b").as_slice().slice_to(16),
+            "thing=s:s%3Aa.b.");
+    }
+
+    #[test]
+    fn check_json_prefix() {
+        // Values beginning `j:` are escaped so as not to be read as JSON
+        let headers = HeaderCollection::empty();
+        assert_eq!(get_cookie(headers, None, "thing", "j:{}"), "thing=%6A%3A%7B%7D".to_string());
+        let headers = HeaderCollection::empty();
+        assert_eq!(get_cookie(headers, Some("@zzmp".to_string()), "thing", "j:a").as_slice().slice_to(16),
+            "thing=s:%6A%3Aa.");
+    }
+
+    #[test]
+    fn check_spaces() {
+        // Spaces are percent-encoded, so that `+` is left unambiguous
+        let headers = HeaderCollection::empty();
+        assert_eq!(get_cookie(headers, None, "a b", "a b+c"), "a%20b=a%20b%2Bc".to_string());
+    }
+
+    #[test]
+    fn check_express_signature() {
+        let mut res = response::new();
+        let mut signer = Cookie::new(Some("tobiiscool".to_string()));
+        signer.signature_format = ExpressSignature;
+        res.set_cookie(&signer, ("thing".to_string(), "hello".to_string()), HeaderCollection::empty()).unwrap();
+        assert_eq!(res.headers.extensions.find(&"Set-Cookie".to_string()).unwrap().clone(),
+            // As signed by Express' `cookie-signature`, with secret "tobiiscool"
+            "thing=s:hello.DGDUkGlIkCzPz+C0B064FNgHdEjox7ch8tOBGslZ5QI".to_string());
+    }
 }
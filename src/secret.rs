@@ -0,0 +1,213 @@
+//! Secrets - validate and generate signing secrets
+
+use std::io::{File, IoResult, IoError, PathAlreadyExists, Truncate, Write};
+use std::io::fs;
+use std::rand::{Rng, OsRng};
+use libc;
+use std::ascii::AsciiExt;
+use serialize::hex::ToHex;
+
+/// The minimum length of a secret, in bytes.
+pub static MIN_SECRET_LEN: uint = 32;
+
+// The minimum estimated entropy of a secret, in bits
+static MIN_SECRET_ENTROPY: f64 = 96.0;
+
+// Values copied from documentation and tutorials, which are never secret
+static PLACEHOLDERS: &'static [&'static str] = &[
+    "changeme", "change me", "change-me", "change_me", "replace", "placeholder", "example",
+    "keyboard cat", "your secret", "your-secret", "your_secret", "secret key", "password"
+];
+
+/// The reason a secret was rejected as too weak to sign cookies.
+#[deriving(Clone, PartialEq, Show)]
+pub enum SecretError {
+    /// The secret is shorter than `MIN_SECRET_LEN` bytes.
+    SecretTooShort,
+    /// The secret is repetitive, so is far easier to guess than its length suggests.
+    LowEntropySecret,
+    /// The secret looks like a placeholder from documentation, such as `changeme`.
+    PlaceholderSecret
+}
+
+/// Check that `secret` is strong enough to sign cookies.
+///
+/// Secrets must be at least `MIN_SECRET_LEN` bytes, must not be repetitive,
+/// and must not contain common placeholders. The entropy estimate is rough,
+/// and only catches obviously weak secrets: use `generate_secret` to be sure.
+pub fn check_secret(secret: &str) -> Result<(), SecretError> {
+    if secret.len() < MIN_SECRET_LEN { return Err(SecretTooShort) }
+    let lowercase = secret.to_ascii_lower();
+    if PLACEHOLDERS.iter().any(|&placeholder| lowercase.as_slice().contains(placeholder)) {
+        return Err(PlaceholderSecret)
+    }
+    if entropy(secret.as_bytes()) < MIN_SECRET_ENTROPY { return Err(LowEntropySecret) }
+    Ok(())
+}
+
+/// Generate a random secret of 256 bits, as 64 hex digits.
+pub fn generate_secret() -> IoResult<String> {
+    let mut rng = try!(OsRng::new());
+    let mut bytes = [0u8, ..32];
+    rng.fill_bytes(bytes);
+    Ok(bytes.as_slice().to_hex())
+}
+
+/// Generate a random secret, and write it to the key file at `path`,
+/// readable only by its owner.
+///
+/// Any existing file is replaced, once the new secret is written.
+pub fn write_secret(path: &Path) -> IoResult<String> {
+    let secret = try!(generate_secret());
+    try!(write_private(path, secret.as_bytes()));
+    Ok(secret)
+}
+
+/// Read a secret from the key file at `path`, ignoring surrounding whitespace.
+pub fn read_secret(path: &Path) -> IoResult<String> {
+    let secret = try!(File::open(path).read_to_string());
+    Ok(secret.as_slice().trim().to_string())
+}
+
+/// Read a secret from the key file at `path`, first generating and writing
+/// a secret, as `write_secret` does, if there is no such file.
+///
+/// If several processes start at once, all read the secret written first.
+pub fn secret_file(path: &Path) -> IoResult<String> {
+    let secret = try!(generate_secret());
+    let temp = try!(write_temp(path, secret.as_bytes()));
+    // Linking fails if the file exists, so an existing secret is never replaced
+    let linked = fs::link(&temp, path);
+    let _ = fs::unlink(&temp);
+    match linked {
+        Ok(())                                        => Ok(secret),
+        Err(ref err) if err.kind == PathAlreadyExists => read_secret(path),
+        Err(err)                                      => Err(err)
+    }
+}
+
+/// Write `bytes` to the file at `path`, readable only by its owner.
+///
+/// The file is written in full before it replaces any existing file,
+/// so an interrupted write leaves the existing file as it was.
+pub fn write_private(path: &Path, bytes: &[u8]) -> IoResult<()> {
+    let temp = try!(write_temp(path, bytes));
+    match fs::rename(&temp, path) {
+        Ok(())   => Ok(()),
+        Err(err) => {
+            let _ = fs::unlink(&temp);
+            Err(err)
+        }
+    }
+}
+
+// Write `bytes` to a new file beside `path`, readable only by its owner
+fn write_temp(path: &Path, bytes: &[u8]) -> IoResult<Path> {
+    let mut rng = try!(OsRng::new());
+    let name = path.filename_str().unwrap_or("file");
+    let temp = path.with_filename(format!(".{}.{:x}.tmp", name, rng.next_u64()));
+    let mut file = try!(create_private(&temp));
+    let written = match file.write(bytes) {
+        Ok(())   => file.fsync(),
+        Err(err) => Err(err)
+    };
+    match written {
+        Ok(())   => Ok(temp),
+        Err(err) => {
+            let _ = fs::unlink(&temp);
+            Err(err)
+        }
+    }
+}
+
+// Create a file readable only by its owner, failing if it exists,
+// so that no other user can open it before it is written
+#[cfg(unix)]
+fn create_private(path: &Path) -> IoResult<File> {
+    let fd = path.with_c_str(|path| unsafe {
+        libc::open(path, libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY, libc::S_IRUSR | libc::S_IWUSR)
+    });
+    if fd < 0 { return Err(IoError::last_error()) }
+    unsafe { libc::close(fd); }
+    File::open_mode(path, Truncate, Write)
+}
+
+// Elsewhere, files cannot be created with an owner-only mode, and take the permissions of their directory
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> IoResult<File> {
+    if path.exists() {
+        return Err(IoError { kind: PathAlreadyExists, desc: "file exists", detail: None })
+    }
+    File::create(path)
+}
+
+// Estimate the entropy of `bytes` in bits, from the frequency of each byte
+fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0u, ..256];
+    for &byte in bytes.iter() {
+        counts[byte as uint] += 1;
+    }
+    let len = bytes.len() as f64;
+    let per_byte = counts.iter().filter(|&&count| count > 0).fold(0.0, |sum, &count| {
+        let p = count as f64 / len;
+        sum - p * p.log2()
+    });
+    per_byte * len
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{TempDir, USER_READ, USER_WRITE};
+    use std::io::fs;
+    use super::*;
+    use super::entropy;
+
+    #[test]
+    fn check_weak_secrets() {
+        assert_eq!(check_secret("@zzmp"), Err(SecretTooShort));
+        assert_eq!(check_secret("abababababababababababababababab"), Err(LowEntropySecret));
+        assert_eq!(check_secret("keyboard cat keyboard cat keyboard cat"), Err(PlaceholderSecret));
+        assert_eq!(check_secret("please CHANGEME before going to production"), Err(PlaceholderSecret));
+        assert_eq!(check_secret("f3a1c94e0b7d2e68a5c0d9b4e1f7a23c"), Ok(()));
+    }
+
+    #[test]
+    fn check_entropy() {
+        assert_eq!(entropy(b"abababababababababababababababab"), 32.0);
+        assert_eq!(entropy(b"aaaa"), 0.0);
+    }
+
+    #[test]
+    fn check_generate() {
+        let secret = generate_secret().unwrap();
+        assert_eq!(secret.len(), 64);
+        assert_eq!(check_secret(secret.as_slice()), Ok(()));
+        assert!(secret != generate_secret().unwrap());
+    }
+
+    #[test]
+    fn check_secret_file() {
+        let dir = TempDir::new("cookie").unwrap();
+        let path = dir.path().join("cookie.key");
+        let secret = secret_file(&path).unwrap();
+        assert_eq!(read_secret(&path).unwrap(), secret);
+        // An existing secret is kept
+        assert_eq!(secret_file(&path).unwrap(), secret);
+        assert_eq!(fs::readdir(dir.path()).unwrap(), vec![path.clone()]);
+    }
+
+    #[test]
+    fn check_write_secret() {
+        let dir = TempDir::new("cookie").unwrap();
+        let path = dir.path().join("cookie.key");
+        let first = write_secret(&path).unwrap();
+        // An existing secret is replaced
+        let second = write_secret(&path).unwrap();
+        assert!(first != second);
+        assert_eq!(read_secret(&path).unwrap(), second);
+        if cfg!(unix) {
+            assert_eq!(fs::stat(&path).unwrap().perm, USER_READ | USER_WRITE);
+        }
+        assert_eq!(fs::readdir(dir.path()).unwrap(), vec![path.clone()]);
+    }
+}
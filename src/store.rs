@@ -0,0 +1,438 @@
+//! Client storage - keep cookies set by servers, and send them back
+
+use std::ascii::AsciiExt;
+use std::io::{File, IoResult, IoError, InvalidInput};
+use time;
+use url::Url;
+use serialize::json;
+use secret::write_private;
+use parser::split_set_cookie;
+use super::HeaderCollection;
+
+/// A cookie kept by a `CookieStore`.
+///
+/// This follows the storage model of [RFC 6265, section 5.3](http://tools.ietf.org/html/rfc6265#section-5.3).
+/// Times are in seconds since the Unix epoch.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct StoredCookie {
+    /// The cookie name, as set.
+    pub name: String,
+    /// The cookie value, as set.
+    pub value: String,
+    /// The domain the cookie is sent to, without any leading `.`.
+    pub domain: String,
+    /// The path the cookie is sent to, and below.
+    pub path: String,
+    /// The time the cookie expires, if it is persistent.
+    pub expires: Option<i64>,
+    /// The time the cookie was first set.
+    ///
+    /// This is kept when the cookie is replaced.
+    pub creation: i64,
+    /// The time the cookie was last sent.
+    pub last_access: i64,
+    /// True if the cookie is kept across sessions, having been set with `Expires` or `Max-Age`.
+    pub persistent: bool,
+    /// True if the cookie is sent only to the host which set it, having been set without `Domain`.
+    pub host_only: bool,
+    /// True if the cookie is sent only over secure connections.
+    pub secure: bool,
+    /// True if the cookie is hidden from scripts.
+    pub http_only: bool
+}
+
+impl StoredCookie {
+    /// True if the cookie has expired at `now`.
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+
+    // True if the cookie should be sent with a request for `host` and `path`
+    fn matches(&self, host: &str, path: &str, secure: bool) -> bool {
+        let domain = if self.host_only { host == self.domain.as_slice() } else { domain_match(host, self.domain.as_slice()) };
+        domain && path_match(path, self.path.as_slice()) && (secure || !self.secure)
+    }
+}
+
+/// The cookies set by servers, as kept by a client such as a browser.
+///
+/// Use this to keep a session across requests to Iron, or any other server:
+/// record each Set-Cookie header with `set_cookie`, and send the `cookie_header` with the next request.
+///
+/// The list of public suffixes is not known: servers are kept from setting cookies
+/// for single labels such as `com`, but not for suffixes such as `co.uk`.
+#[deriving(Clone, Show, Encodable, Decodable)]
+pub struct CookieStore {
+    cookies: Vec<StoredCookie>
+}
+
+impl CookieStore {
+    /// Create an empty store.
+    pub fn new() -> CookieStore {
+        CookieStore { cookies: Vec::new() }
+    }
+
+    /// Every cookie stored, in the order first set.
+    pub fn cookies(&self) -> &[StoredCookie] {
+        self.cookies.as_slice()
+    }
+
+    /// Store a cookie set by the Set-Cookie `header` of a response from `url`.
+    ///
+    /// Returns false if the cookie was ignored, as when its `Domain` does not match
+    /// the host, or its name's prefix is not satisfied. Cookies set to expire
+    /// remove any stored cookie of the same name, domain and path.
+    pub fn set_cookie(&mut self, url: &Url, header: &str) -> bool {
+        self.set_cookie_at(url, header, time::get_time().sec)
+    }
+
+    /// The Cookie header to send with a request for `url`, if any cookies match it.
+    ///
+    /// Cookies with longer paths are sent first, then those set earliest,
+    /// as described by [RFC 6265, section 5.4](http://tools.ietf.org/html/rfc6265#section-5.4).
+    pub fn cookie_header(&mut self, url: &Url) -> Option<String> {
+        self.cookie_header_at(url, time::get_time().sec)
+    }
+
+    /// Store `cookie`, replacing any of the same name, domain and path but keeping its creation time.
+    pub fn insert(&mut self, cookie: StoredCookie) {
+        let mut cookie = cookie;
+        match self.position(&cookie) {
+            Some(i) => {
+                cookie.creation = self.cookies[i].creation;
+                *self.cookies.get_mut(i) = cookie;
+            },
+            None    => self.cookies.push(cookie)
+        }
+    }
+
+    /// Remove every cookie which has expired.
+    pub fn remove_expired(&mut self) {
+        self.remove_expired_at(time::get_time().sec)
+    }
+
+    /// Write every cookie to the file at `path` as JSON, readable only by its owner.
+    ///
+    /// Session cookies are saved along with persistent cookies, so that
+    /// command-line tools can keep a session across runs. Any existing file
+    /// is replaced only once the store is written in full.
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        write_private(path, json::encode(self).as_bytes())
+    }
+
+    /// Read the cookies saved to the file at `path` by `save`, removing any which have expired.
+    pub fn load(path: &Path) -> IoResult<CookieStore> {
+        let saved = try!(File::open(path).read_to_string());
+        CookieStore::from_saved(saved.as_slice(), time::get_time().sec)
+    }
+
+    fn from_saved(saved: &str, now: i64) -> IoResult<CookieStore> {
+        let mut store: CookieStore = try!(json::decode(saved).map_err(|err| IoError {
+            kind: InvalidInput,
+            desc: "invalid cookie store",
+            detail: Some(err.to_string())
+        }));
+        store.remove_expired_at(now);
+        Ok(store)
+    }
+
+    fn remove_expired_at(&mut self, now: i64) {
+        self.cookies.retain(|cookie| !cookie.is_expired(now));
+    }
+
+    fn position(&self, cookie: &StoredCookie) -> Option<uint> {
+        self.cookies.iter().position(|stored| {
+            stored.name == cookie.name && stored.domain == cookie.domain && stored.path == cookie.path
+        })
+    }
+
+    fn set_cookie_at(&mut self, url: &Url, header: &str, now: i64) -> bool {
+        let host = match url.serialize_host() {
+            Some(host) => host.as_slice().to_ascii_lower(),
+            None       => return false
+        };
+        let request_path = url.serialize_path().unwrap_or("/".to_string());
+        let set = match parse_set_cookie(header, now) {
+            Some(set) => set,
+            None      => return false
+        };
+
+        // Prefixes are checked against the attributes as set
+        let mut headers = HeaderCollection::empty();
+        headers.secure = set.secure;
+        headers.domain = set.domain.clone();
+        headers.path = set.path.clone();
+        if headers.check_prefix(set.name.as_slice()).is_err() { return false }
+
+        let (domain, host_only) = match set.domain {
+            // Single labels are taken as public suffixes, which are host-only if set at all
+            Some(ref domain) if !domain.as_slice().contains_char('.') && *domain == host => (host.clone(), true),
+            Some(ref domain) if !domain.as_slice().contains_char('.')            => return false,
+            Some(ref domain) if domain_match(host.as_slice(), domain.as_slice()) => (domain.clone(), false),
+            Some(_)                                                              => return false,
+            None                                                                 => (host, true)
+        };
+        let cookie = StoredCookie {
+            name: set.name,
+            value: set.value,
+            domain: domain,
+            path: set.path.unwrap_or_else(|| default_path(request_path.as_slice())),
+            persistent: set.expires.is_some(),
+            expires: set.expires,
+            creation: now,
+            last_access: now,
+            host_only: host_only,
+            secure: set.secure,
+            http_only: set.http_only
+        };
+        if cookie.is_expired(now) {
+            match self.position(&cookie) {
+                Some(i) => { self.cookies.remove(i); },
+                None    => ()
+            }
+        } else {
+            self.insert(cookie);
+        }
+        true
+    }
+
+    fn cookie_header_at(&mut self, url: &Url, now: i64) -> Option<String> {
+        let host = match url.serialize_host() {
+            Some(host) => host.as_slice().to_ascii_lower(),
+            None       => return None
+        };
+        let path = url.serialize_path().unwrap_or("/".to_string());
+        let secure = url.scheme.as_slice() == "https";
+
+        let mut cookies: Vec<&mut StoredCookie> = self.cookies
+            .iter_mut()
+            .filter(|cookie| !cookie.is_expired(now) && cookie.matches(host.as_slice(), path.as_slice(), secure))
+            .collect();
+        if cookies.is_empty() { return None }
+        // The sort is stable, so cookies of equal paths stay in the order they were first set
+        cookies.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        let pairs: Vec<String> = cookies.iter_mut().map(|cookie| {
+            cookie.last_access = now;
+            cookie.name.clone().append("=").append(cookie.value.as_slice())
+        }).collect();
+        Some(pairs.connect("; "))
+    }
+}
+
+// The attributes of a Set-Cookie header which affect storage
+pub struct ParsedSetCookie {
+    pub name: String,
+    pub value: String,
+    pub expires: Option<i64>,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub secure: bool,
+    pub http_only: bool
+}
+
+// Parse a Set-Cookie header, as described by RFC 6265, section 5.2.
+//
+// `Max-Age` takes precedence over `Expires`, and is relative to `now`.
+pub fn parse_set_cookie(header: &str, now: i64) -> Option<ParsedSetCookie> {
+    let ((name, value), attributes) = match split_set_cookie(header) {
+        Some(split) => split,
+        None        => return None
+    };
+    let mut cookie = ParsedSetCookie {
+        name: name.to_string(),
+        value: value.to_string(),
+        expires: None,
+        domain: None,
+        path: None,
+        secure: false,
+        http_only: false
+    };
+    let mut max_age = None;
+    for (key, value) in attributes.into_iter() {
+        let value = value.unwrap_or("");
+        match key.to_ascii_lower().as_slice() {
+            "expires"  => match parse_date(value) {
+                Some(expires) => cookie.expires = Some(expires),
+                None          => ()
+            },
+            "max-age"  => match from_str::<i64>(value) {
+                Some(age) if value.chars().all(|c| c.is_digit() || c == '-') => max_age = Some(age),
+                _                                                            => ()
+            },
+            "domain"   => {
+                let domain = if value.starts_with(".") { value.slice_from(1) } else { value };
+                cookie.domain = if domain.is_empty() { None } else { Some(domain.to_ascii_lower()) };
+            },
+            "path"     => cookie.path = if value.starts_with("/") { Some(value.to_string()) } else { None },
+            "secure"   => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            _          => ()
+        }
+    }
+    match max_age {
+        // A `Max-Age` of zero or less expires the cookie immediately
+        Some(age) => cookie.expires = Some(if age <= 0 { 0 } else { now + age }),
+        None      => ()
+    }
+    Some(cookie)
+}
+
+/// Parse a cookie date, in the formats written by servers in practice.
+pub fn parse_date(date: &str) -> Option<i64> {
+    let formats = ["%a, %d %b %Y %H:%M:%S GMT", "%a, %d-%b-%Y %H:%M:%S GMT",
+                   "%A, %d-%b-%y %H:%M:%S GMT", "%a %b %e %H:%M:%S %Y"];
+    formats.iter()
+        .filter_map(|format| time::strptime(date, *format).ok())
+        .next()
+        .map(|tm| tm.to_timespec().sec)
+}
+
+/// Format a cookie date, as `HeaderCollection` writes `Expires`.
+pub fn format_date(date: i64) -> String {
+    time::at_utc(time::Timespec::new(date, 0)).rfc822()
+}
+
+// The default path of a cookie set from `path`, as described by RFC 6265, section 5.1.4
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(i) if i > 0 && path.starts_with("/") => path.slice_to(i).to_string(),
+        _                                         => "/".to_string()
+    }
+}
+
+// Path-matching, as described by RFC 6265, section 5.1.4
+fn path_match(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path ||
+    request_path.starts_with(cookie_path) &&
+        (cookie_path.ends_with("/") || request_path.slice_from(cookie_path.len()).starts_with("/"))
+}
+
+// Domain-matching, as described by RFC 6265, section 5.1.3
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain ||
+    host.ends_with(domain) && host.slice_to(host.len() - domain.len()).ends_with(".") &&
+        !host.chars().all(|c| c.is_digit() || c == '.')
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{File, TempDir, USER_READ, USER_WRITE};
+    use std::io::fs;
+    use url::Url;
+    use super::*;
+    use super::{default_path, path_match, domain_match, parse_date};
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn check_matching() {
+        assert_eq!(default_path("/a/b/c"), "/a/b".to_string());
+        assert_eq!(default_path("/a"), "/".to_string());
+        assert_eq!(default_path(""), "/".to_string());
+        assert!(path_match("/a/b", "/a"));
+        assert!(path_match("/a/b", "/a/"));
+        assert!(!path_match("/ab", "/a"));
+        assert!(domain_match("www.example.com", "example.com"));
+        assert!(!domain_match("badexample.com", "example.com"));
+        assert!(!domain_match("1.2.3.4", "2.3.4"));
+    }
+
+    #[test]
+    fn check_dates() {
+        assert_eq!(parse_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_date("Sun, 06-Nov-1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_date("soon"), None);
+        assert_eq!(parse_date(super::format_date(784111777).as_slice()), Some(784111777));
+    }
+
+    #[test]
+    fn check_store() {
+        let mut store = CookieStore::new();
+        let site = url("https://www.example.com/account/login");
+        assert!(store.set_cookie_at(&site, "sid=abc; Secure; HttpOnly", 1000));
+        assert!(store.set_cookie_at(&site, "theme=dark; Domain=.example.com; Path=/; Max-Age=60", 1000));
+        assert!(store.set_cookie_at(&site, "recent=1", 1001));
+        // Cookies may not be set for other domains
+        assert!(!store.set_cookie_at(&site, "evil=1; Domain=example.org", 1001));
+        assert!(!store.set_cookie_at(&site, "evil=1; Domain=com", 1001));
+
+        assert_eq!(store.cookies()[0], StoredCookie {
+            name: "sid".to_string(),
+            value: "abc".to_string(),
+            domain: "www.example.com".to_string(),
+            path: "/account".to_string(),
+            expires: None,
+            creation: 1000,
+            last_access: 1000,
+            persistent: false,
+            host_only: true,
+            secure: true,
+            http_only: true
+        });
+        assert_eq!(store.cookies()[1].expires, Some(1060));
+        assert!(store.cookies()[1].persistent && !store.cookies()[1].host_only);
+
+        assert_eq!(store.cookie_header_at(&site, 1010), Some("sid=abc; recent=1; theme=dark".to_string()));
+        assert_eq!(store.cookies()[0].last_access, 1010);
+        // Host-only and secure cookies are kept from other hosts and insecure requests
+        assert_eq!(store.cookie_header_at(&url("http://shop.example.com/account"), 1010), Some("theme=dark".to_string()));
+        assert_eq!(store.cookie_header_at(&url("http://www.example.com/account"), 1010),
+                   Some("recent=1; theme=dark".to_string()));
+        // Expired cookies are not sent
+        assert_eq!(store.cookie_header_at(&url("http://shop.example.com/"), 1060), None);
+    }
+
+    #[test]
+    fn check_replace() {
+        let mut store = CookieStore::new();
+        let site = url("https://example.com/");
+        store.set_cookie_at(&site, "sid=abc", 1000);
+        store.set_cookie_at(&site, "sid=def", 2000);
+        assert_eq!(store.cookies().len(), 1);
+        assert_eq!((store.cookies()[0].value.as_slice(), store.cookies()[0].creation), ("def", 1000));
+        // Expiring a cookie removes it
+        store.set_cookie_at(&site, "sid=; Max-Age=0", 3000);
+        assert_eq!(store.cookies().len(), 0);
+    }
+
+    #[test]
+    fn check_prefixes() {
+        let mut store = CookieStore::new();
+        let site = url("https://example.com/");
+        assert!(!store.set_cookie_at(&site, "__Host-sid=abc; Secure", 1000));
+        assert!(store.set_cookie_at(&site, "__Host-sid=abc; Secure; Path=/", 1000));
+    }
+
+    #[test]
+    fn check_save() {
+        let mut store = CookieStore::new();
+        let site = url("https://www.example.com/");
+        store.set_cookie_at(&site, "sid=abc; Secure; HttpOnly", 1000);
+        store.set_cookie_at(&site, "theme=dark; Domain=example.com; Max-Age=60", 1000);
+        store.set_cookie_at(&site, "recent=1; Max-Age=3600", 1000);
+        store.cookie_header_at(&site, 1030);
+
+        let dir = TempDir::new("cookie").unwrap();
+        let path = dir.path().join("cookies.json");
+        store.save(&path).unwrap();
+        let saved = File::open(&path).read_to_string().unwrap();
+        // Every field is kept, and expired cookies are removed
+        let loaded = CookieStore::from_saved(saved.as_slice(), 1030).unwrap();
+        assert_eq!(loaded.cookies(), store.cookies());
+        let loaded = CookieStore::from_saved(saved.as_slice(), 1060).unwrap();
+        assert_eq!(loaded.cookies(), [store.cookies()[0].clone(), store.cookies()[2].clone()].as_slice());
+
+        // Only the session cookie has not expired by now
+        assert_eq!(CookieStore::load(&path).unwrap().cookies(), [store.cookies()[0].clone()].as_slice());
+        if cfg!(unix) {
+            assert_eq!(fs::stat(&path).unwrap().perm, USER_READ | USER_WRITE);
+        }
+        // Saving again replaces the file
+        CookieStore::new().save(&path).unwrap();
+        assert_eq!(CookieStore::load(&path).unwrap().cookies().len(), 0);
+        assert_eq!(fs::readdir(dir.path()).unwrap(), vec![path.clone()]);
+        assert!(CookieStore::from_saved("{\"cookies\": [{\"name\": \"sid\"}]}", 1000).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+//! Testing utilities - mock requests with cookies, and structured Set-Cookie headers
+//!
+//! This module is built with the `testing` feature, for your tests only:
+//!
+//! ```toml
+//! [dev-dependencies.cookie]
+//! git = "https://github.com/iron/cookie.git"
+//! features = ["testing"]
+//! ```
+
+use std::ascii::AsciiExt;
+use std::collections::TreeMap;
+use serialize::json;
+use serialize::json::Json;
+use iron::{Request, Response, Middleware};
+use iron_test::mock::{request, response};
+use super::{Cookie, CookieParser, SetCookie, HeaderCollection, Verification, Lenient};
+use parser::{split_set_cookie, decode_pair, is_json, is_escaped_json};
+
+/// Build a mock `GET` request with the Cookie `header`.
+pub fn request_with_header(header: &str) -> Request {
+    let mut req = request::new(::http::method::Get, "localhost:3000");
+    req.headers.extensions.insert("Cookie".to_string(), header.to_string());
+    req
+}
+
+/// Build a mock `GET` request carrying `cookies`, as a browser would send them
+/// after they were set through `signer`.
+///
+/// Cookies are encoded, and signed if `signer` has a secret, exactly as by `SetCookie`:
+/// use `Cookie::new(Some(secret))` for cookies pre-signed with `secret`.
+pub fn request_with_cookies(signer: &Cookie, cookies: &[(&str, &str)]) -> Request {
+    request_with_header(cookie_header(signer, cookies).as_slice())
+}
+
+/// Write the Cookie header a browser would send with `cookies`, set through `signer`.
+///
+/// Cookies named with a `__Secure-` or `__Host-` prefix are set with headers satisfying it.
+pub fn cookie_header(signer: &Cookie, cookies: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = cookies.iter().map(|&(name, value)| {
+        // Attributes are not sent back by browsers, but must satisfy any prefix to be set
+        let headers = if name.starts_with("__Host-") {
+            HeaderCollection::host_prefixed()
+        } else if name.starts_with("__Secure-") {
+            HeaderCollection::secure_prefixed()
+        } else {
+            HeaderCollection::empty()
+        };
+        let mut res = response::new();
+        res.set_cookie(signer, (name.to_string(), value.to_string()), headers).unwrap();
+        let header = res.headers.extensions.find(&"Set-Cookie".to_string()).unwrap();
+        header.as_slice().split(';').next().unwrap().to_string()
+    }).collect();
+    pairs.connect("; ")
+}
+
+/// Run the request through `parser`, and find the `Cookie` it parsed.
+///
+/// Fails if the parser is `lazy`; use `req.alloy.find::<LazyCookie>()` instead.
+pub fn parse<'a>(parser: &mut CookieParser, req: &'a mut Request) -> &'a Cookie {
+    parser.enter(req, &mut response::new());
+    req.alloy.find::<Cookie>().expect("lazy parsers store a LazyCookie")
+}
+
+/// A Set-Cookie header, parsed for comparison with an expected cookie.
+///
+/// Attribute names are matched case-insensitively.
+#[deriving(Clone, PartialEq, Show)]
+pub struct SetCookieHeader {
+    /// The decoded cookie name.
+    pub name: String,
+    /// The decoded cookie value, or `None` if it could not be verified.
+    pub value: Option<String>,
+    /// The outcome of verifying the value's signature.
+    pub verification: Verification,
+    /// True if the value was set as JSON, rather than a plain value beginning `j:`.
+    pub json: bool,
+    /// The `Expires` attribute, as written.
+    pub expires: Option<String>,
+    /// The `Max-Age` attribute.
+    pub max_age: Option<u32>,
+    /// The `Domain` attribute.
+    pub domain: Option<String>,
+    /// The `Path` attribute.
+    pub path: Option<String>,
+    /// True if the cookie is `Secure`.
+    pub secure: bool,
+    /// True if the cookie is `HttpOnly`.
+    pub http_only: bool,
+    /// Any other attributes, by name as written.
+    pub extensions: TreeMap<String, Option<String>>
+}
+
+impl SetCookieHeader {
+    /// Parse a Set-Cookie `header`, decoding and verifying its value as `signer` would.
+    ///
+    /// Returns `None` if the header has no cookie-pair.
+    pub fn parse(header: &str, signer: &Cookie) -> Option<SetCookieHeader> {
+        let ((name, value), attributes) = match split_set_cookie(header) {
+            Some(split) => split,
+            None        => return None
+        };
+        let escaped = is_escaped_json(value, signer);
+        let (name, value, verification) = match decode_pair(name, value, &Lenient, signer) {
+            Ok(decoded) => decoded,
+            Err(_)      => return None
+        };
+        let json = value.as_ref().map_or(false, |value| is_json(value.as_slice(), escaped));
+        let mut cookie = SetCookieHeader {
+            name: name,
+            value: value,
+            verification: verification,
+            json: json,
+            expires: None,
+            max_age: None,
+            domain: None,
+            path: None,
+            secure: false,
+            http_only: false,
+            extensions: TreeMap::new()
+        };
+        for (key, value) in attributes.into_iter() {
+            let value = value.map(|value| value.to_string());
+            match key.to_ascii_lower().as_slice() {
+                ""                      => (),
+                "expires"               => cookie.expires = value,
+                "max-age"               => cookie.max_age = value.and_then(|age| from_str(age.as_slice())),
+                "domain"                => cookie.domain = value,
+                "path"                  => cookie.path = value,
+                "secure"                => cookie.secure = true,
+                "httponly" | "http-only" => cookie.http_only = true,
+                _                       => { cookie.extensions.insert(key.to_string(), value); }
+            }
+        }
+        Some(cookie)
+    }
+
+    /// Parse the value as JSON, if it was set as a JSON cookie.
+    pub fn json(&self) -> Option<Json> {
+        match self.value {
+            Some(ref value) if self.json => json::from_str(value.as_slice().slice_from(2)).ok(),
+            _                            => None
+        }
+    }
+}
+
+/// Find the cookie set on the response, decoded and verified as `signer` would.
+pub fn set_cookie(res: &Response, signer: &Cookie) -> Option<SetCookieHeader> {
+    res.headers.extensions
+        .find(&"Set-Cookie".to_string())
+        .and_then(|header| SetCookieHeader::parse(header.as_slice(), signer))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::TreeMap;
+    use serialize::json;
+    use iron_test::mock::response;
+    use super::*;
+    use super::super::{Cookie, CookieParser, SetCookie, HeaderCollection, Verified, Unsigned, InvalidSignature};
+
+    #[test]
+    fn check_request_with_cookies() {
+        let signer = Cookie::new(Some("@zzmp".to_string()));
+        assert_eq!(cookie_header(&signer, &[("thing", "thung"), ("a b", "c")]),
+            "thing=s:thung.e99abddcf60cad18f8d4b993efae53e81410cf2b2855af0309f1ae46fa527fbb; \
+             a%20b=s:c.".to_string().append(signer.sign("a b", &"c".to_string()).unwrap().as_slice()));
+
+        let mut req = request_with_cookies(&signer, &[("thing", "thung"), ("a b", "c")]);
+        let cookie = parse(&mut CookieParser::signed_unchecked("@zzmp".to_string()), &mut req);
+        assert_eq!(cookie.get("thing"), Some(&"thung".to_string()));
+        assert_eq!(cookie.get("a b"), Some(&"c".to_string()));
+    }
+
+    #[test]
+    fn check_prefixed_cookies() {
+        let signer = Cookie::new(Some("@zzmp".to_string()));
+        let mut req = request_with_cookies(&signer, &[("__Host-session", "abc"), ("__Secure-token", "def")]);
+        let cookie = parse(&mut CookieParser::signed_unchecked("@zzmp".to_string()), &mut req);
+        assert_eq!(cookie.get("__Host-session"), Some(&"abc".to_string()));
+        assert_eq!(cookie.get("__Secure-token"), Some(&"def".to_string()));
+    }
+
+    #[test]
+    fn check_set_cookie() {
+        let signer = Cookie::new(Some("@zzmp".to_string()));
+        let mut res = response::new();
+        let mut headers = HeaderCollection::aged(42);
+        headers.path = Some("/".to_string());
+        headers.http_only = true;
+        let mut extensions = TreeMap::new();
+        extensions.insert("SameSite".to_string(), Some("Lax".to_string()));
+        headers.extensions = Some(extensions.clone());
+        res.set_cookie(&signer, ("thing".to_string(), "thung".to_string()), headers).unwrap();
+        assert_eq!(set_cookie(&res, &signer), Some(SetCookieHeader {
+            name: "thing".to_string(),
+            value: Some("thung".to_string()),
+            verification: Verified,
+            json: false,
+            expires: None,
+            max_age: Some(42),
+            domain: None,
+            path: Some("/".to_string()),
+            secure: false,
+            http_only: true,
+            extensions: extensions
+        }));
+
+        // Signatures are verified with the given signer's secret
+        let other = Cookie::new(Some("other secret".to_string()));
+        let cookie = set_cookie(&res, &other).unwrap();
+        assert_eq!((cookie.value, cookie.verification), (None, InvalidSignature));
+    }
+
+    #[test]
+    fn check_set_json_cookie() {
+        let signer = Cookie::new(None);
+        let mut res = response::new();
+        let json = json::from_str("{\"a\":[1,\"b\"]}").unwrap();
+        res.set_json_cookie(&signer, ("thing".to_string(), json.clone()), HeaderCollection::secured()).unwrap();
+        let cookie = set_cookie(&res, &signer).unwrap();
+        assert_eq!(cookie.json(), Some(json));
+        assert_eq!(cookie.verification, Unsigned);
+        assert!(cookie.secure && cookie.http_only);
+
+        // Plain values beginning `j:` are not JSON
+        res.set_cookie(&signer, ("thing".to_string(), "j:{}".to_string()), HeaderCollection::empty()).unwrap();
+        let cookie = set_cookie(&res, &signer).unwrap();
+        assert_eq!(cookie.value, Some("j:{}".to_string()));
+        assert_eq!(cookie.json(), None);
+    }
+}